@@ -0,0 +1,1113 @@
+//! The command line options
+
+use parallelion::{Backend, Header, Rule, Source, SshLogin, Timeout, Trim};
+use regex::Regex;
+use std::{ffi::OsString, path::PathBuf, str::FromStr, time::Duration as StdDuration};
+use structopt::{clap::AppSettings, StructOpt};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "parallelion",
+    about = "An example of StructOpt usage.",
+    raw(global_settings = "&[AppSettings::DisableVersion]")
+)]
+pub struct Opts {
+    /// Show progress
+    ///
+    /// Displays % of jobs completed, ETA, number of jobs running, number of jobs started. When
+    /// the number of jobs isn't known, only the number of jobs completed, running and started is
+    /// displayed
+    #[structopt(short, long)]
+    pub progress: bool,
+    /// The characters of the progress bar, from a full cell to an empty one (e.g. '#- ')
+    ///
+    /// The default is the one of the --bar-style.
+    #[structopt(long = "bar-chars", parse(try_from_str = "parse_bar_chars"))]
+    pub bar_chars: Option<String>,
+    /// The look of the progress bar: 'unicode' for a colored bar of Unicode blocks, 'ascii' for a
+    /// bar of '=> ' without colors, 'minimal' for the counts alone or 'percent-only'
+    ///
+    /// The default is 'unicode' when the terminal can display it, and 'ascii' otherwise.
+    #[structopt(long = "bar-style")]
+    pub bar_style: Option<BarStyle>,
+    /// Show the estimated time until all the jobs are done. Implies --progress
+    ///
+    /// When the number of jobs isn't known, the estimate is based on the duration of the last jobs
+    /// and the number of jobs waiting for a worker.
+    #[structopt(long = "eta")]
+    pub eta: bool,
+    /// How many jobs to expect when they can't be counted in advance, like when the arguments
+    /// are read from stdin, so that --progress shows a bar
+    #[structopt(long = "total-jobs")]
+    pub total_jobs: Option<usize>,
+    /// How to show the progress: 'bar', or 'json' for a JSON object per line with the number of
+    /// jobs started, running, completed and failed, and the estimated seconds left. 'json'
+    /// implies --progress
+    #[structopt(long = "progress-format", default_value = "bar")]
+    pub progress_format: ProgressFormat,
+    /// Write the progress to the given file descriptor instead of the terminal. Implies
+    /// --progress
+    ///
+    /// The bar is then a line of text written at each update.
+    #[structopt(long = "progress-fd", conflicts_with = "progress_file")]
+    pub progress_fd: Option<i32>,
+    /// Write the progress to the given file instead of the terminal. Implies --progress
+    ///
+    /// The bar is then a line of text written at each update.
+    #[structopt(long = "progress-file", parse(from_os_str))]
+    pub progress_file: Option<PathBuf>,
+    /// How often the progress is drawn (e.g. 1s), every 200ms for the bar and every 500ms for
+    /// the lines written elsewhere by default
+    ///
+    /// The jobs are still counted as soon as they are done, for the next time it is drawn. The
+    /// bar is only drawn at this interval, rather than whenever indicatif would, and it can't be
+    /// 0.
+    #[structopt(long = "progress-interval", parse(try_from_str = "parse_interval"))]
+    pub progress_interval: Option<StdDuration>,
+
+    /// Silence all output
+    #[structopt(short = "q", long = "quiet")]
+    pub quiet: bool,
+    /// Increase verbosity (0 = normal, 1 = info, 2 = debug)
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: usize,
+    /// Log the executed jobs to the following file
+    ///
+    /// By default, each line is a json with the following fields: sequence number (seq), start time
+    /// (start), duration in floating-point seconds (duration), command run (cmd), exit status (exit_code),
+    /// number of times the command was run (attempts)
+    ///
+    /// A path starting with '+' (e.g. '+jobs.log') is appended to instead of being overwritten.
+    /// The joblog is locked during the run, so a run sharing it waits for the other to be done.
+    #[structopt(short, long, alias = "joblog", parse(from_os_str))]
+    pub log: Option<PathBuf>,
+    /// The format of the joblog: 'json', or 'tsv' for the one of GNU parallel
+    ///
+    /// The tsv joblog starts with a header row, then has a row per job with the columns Seq,
+    /// Host, Starttime, JobRuntime, Send, Receive, Exitval, Signal and Command. Seq starts at 1,
+    /// like '{#}', and Send, Receive and Signal are always 0. The tabs, newlines and backslashes of
+    /// the command are escaped as '\t', '\n' and '\\', like in GNU parallel.
+    #[structopt(long = "joblog-format", default_value = "json")]
+    pub joblog_format: JoblogFormat,
+    /// Write a csv summary of the run to the following file once it is done
+    ///
+    /// It has a header row, then a row per job in the order of the input with the columns seq,
+    /// arg, exit_code and duration_seconds. Seq starts at 1, like '{#}'.
+    #[structopt(long = "summary-csv", parse(from_os_str))]
+    pub summary_csv: Option<PathBuf>,
+    /// Write the human-readable log messages to the following file
+    ///
+    /// This must be a different file than the one given to --log
+    #[structopt(long = "debug-log", parse(from_os_str))]
+    pub debug_log: Option<PathBuf>,
+    /// Save the output of each job in the given directory
+    ///
+    /// The stdout and stderr of the job with the sequence number seq are written to 'dir/seq/stdout'
+    /// and 'dir/seq/stderr', or 'stdout.gz' and 'stderr.gz' with --compress, and its joblog record
+    /// to 'dir/seq/meta'. Seq starts at 0, like in the joblog. The output must be grouped.
+    #[structopt(long = "results", parse(from_os_str))]
+    pub results: Option<PathBuf>,
+    /// How the files of --results are named: 'tree' for the directory of each job above, 'flat'
+    /// for 'dir/seq.out', 'dir/seq.err' and 'dir/seq.meta', or 'by-arg' for the directory of each
+    /// job named after its arguments instead of its sequence number
+    ///
+    /// With 'by-arg', a single argument of letters, digits, '.', '-' and '_' is the name of the
+    /// directory as is. Otherwise, the other characters are replaced with '_' and a hash of the
+    /// arguments is appended, so that only the jobs with the same arguments write to the same
+    /// directory. The default is 'tree'.
+    #[structopt(long = "results-format", requires = "results")]
+    pub results_format: Option<ResultsFormat>,
+
+    /// Skip the jobs already recorded in the joblog, and append the new ones to it
+    ///
+    /// The jobs are identified by their sequence number, so the input must be the same as the one
+    /// of the previous run.
+    #[structopt(long = "resume")]
+    pub resume: bool,
+    /// Like --resume, but run again the jobs that failed in the previous run
+    #[structopt(long = "resume-failed")]
+    pub resume_failed: bool,
+
+    /// Print the jobs to stdout, but don't execute them
+    ///
+    /// Nothing is written to the joblog or to the directory of --results.
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+    /// Like --dry-run, but print the plan of the run instead of the commands
+    ///
+    /// Once the run is done, the plan has a row per job with its sequence number, the slot it was
+    /// given, its host with --sshlogin, the value of each token of the command and the command, as
+    /// aligned columns under a header row. With --progress-format json, it is a json object per
+    /// job with the fields seq, slot, host, tokens and cmd instead.
+    #[structopt(long = "plan")]
+    pub plan: bool,
+    /// Prefix each line of output of the jobs with their argument and a tab
+    ///
+    /// The commands are run with an external ion process, which must be in the PATH.
+    #[structopt(long = "tag")]
+    pub tag: bool,
+    /// Use the given string as the tag instead of the argument. It can contain the same tokens as
+    /// the command. Implies --tag
+    #[structopt(long = "tagstring")]
+    pub tagstring: Option<String>,
+    /// Prefix each line of output of the jobs with their sequence number and a colon, like '1:'
+    #[structopt(
+        long = "output-prefix",
+        alias = "number-lines",
+        conflicts_with = "tag",
+        conflicts_with = "tagstring"
+    )]
+    pub number_lines: bool,
+    /// Write the output of each job at once when it is done, so that the output of different jobs
+    /// isn't mixed. This is the default
+    ///
+    /// The commands are run with an external ion process, which must be in the PATH.
+    #[structopt(long = "group")]
+    pub group: bool,
+    /// Write the output of the jobs as soon as they write it, even if it is mixed with the output
+    /// of other jobs
+    #[structopt(short, long = "ungroup", conflicts_with = "group")]
+    pub ungroup: bool,
+    /// Write the output of the jobs as soon as they write it, but only whole lines, so that the
+    /// lines of different jobs aren't mixed
+    ///
+    /// A last line without a newline is written when the job is done.
+    #[structopt(
+        long = "line-buffer",
+        conflicts_with = "group",
+        conflicts_with = "ungroup"
+    )]
+    pub line_buffer: bool,
+    /// Print each command to stderr just before running it, like 'set -x'
+    #[structopt(long = "show-commands")]
+    pub show_commands: bool,
+
+    /// Keep the same order for the results as for the input arguments
+    ///
+    /// Results are buffered until every job started before them has completed
+    #[structopt(short, long = "keep-order")]
+    pub keep_order: bool,
+    /// Hold back at most n results with their own output with --keep-order, the output of the
+    /// next ones is moved to a single temporary file under --tmpdir until they are written out
+    #[structopt(long = "keep-order-buffer", requires = "keep_order")]
+    pub keep_order_buffer: Option<usize>,
+
+    /// Run a failing command again up to n times
+    #[structopt(long = "retries", default_value = "0")]
+    pub retries: usize,
+    /// Wait for the given duration before running a failing command again (e.g. 1s)
+    #[structopt(long = "retry-delay", parse(try_from_str = "parse_duration"))]
+    pub retry_delay: Option<StdDuration>,
+    /// Multiply the wait by this factor after each retry, 1 by default, so that the n-th retry
+    /// waits 'retry-delay * factor^(n-1)'
+    #[structopt(
+        long = "retry-backoff",
+        requires = "retry_delay",
+        parse(try_from_str = "parse_backoff")
+    )]
+    pub retry_backoff: Option<f64>,
+    /// The longest wait before a retry, however many retries came before
+    #[structopt(
+        long = "retry-max-delay",
+        requires = "retry_delay",
+        parse(try_from_str = "parse_duration")
+    )]
+    pub retry_max_delay: Option<StdDuration>,
+
+    /// Kill the jobs running for longer than the given duration (e.g. 30s, 5m or 1.5h)
+    ///
+    /// A percentage (e.g. 200%) is relative to the median runtime of the jobs done so far, and
+    /// only applies once 3 jobs are done. The jobs that timed out are sent SIGTERM along with the
+    /// processes they started, then SIGKILL a second later, and exit with the status code 124.
+    /// The jobs with a timeout are run with an external ion process, which must be in the PATH.
+    #[structopt(long = "timeout", parse(try_from_str = "parse_timeout"))]
+    pub timeout: Option<Timeout>,
+    /// Stop the run after the given duration (e.g. 10m): no more jobs are started, the running
+    /// ones are sent SIGTERM along with the processes they started, then SIGKILL a second later,
+    /// and parallelion exits with the status code 124
+    #[structopt(long = "total-timeout", parse(try_from_str = "parse_duration"))]
+    pub total_timeout: Option<StdDuration>,
+
+    /// Wait for the given duration between the start of two jobs (e.g. 0.5 or 2s)
+    #[structopt(long = "delay", parse(try_from_str = "parse_duration"))]
+    pub delay: Option<StdDuration>,
+    /// Vary the delay at random by up to the given duration, more or less, so that the jobs don't
+    /// start in step (e.g. '--delay 1s --jitter 0.5s')
+    #[structopt(
+        long = "jitter",
+        parse(try_from_str = "parse_duration"),
+        requires = "delay"
+    )]
+    pub jitter: Option<StdDuration>,
+
+    /// Don't start new jobs while the load average of the last minute is above the given value
+    ///
+    /// The maximum is either a load (e.g. 4) or a percentage of the cores (e.g. 80%).
+    #[structopt(long = "load")]
+    pub load: Option<MaxLoad>,
+
+    /// Don't start new jobs while less memory than the given size is available (e.g. 500M or 2G)
+    #[structopt(long = "memfree", parse(try_from_str = "parse_size"))]
+    pub memfree: Option<usize>,
+
+    /// Run the jobs with their niceness increased by n, so that they don't slow the rest of the
+    /// system down. Negative values require root privileges
+    #[structopt(long = "nice", allow_hyphen_values = true)]
+    pub nice: Option<i32>,
+
+    /// Run the jobs in the given directory, which can contain the same tokens as the command
+    ///
+    /// For instance, '--workdir {//}' runs each job in the directory of its argument. The
+    /// directory must exist, and on the hosts of --sshlogin it is entered before the command.
+    #[structopt(long = "workdir", alias = "wd")]
+    pub workdir: Option<String>,
+
+    /// Only pass the given environment variable to the jobs, along with PATH. Can be repeated
+    ///
+    /// '_' stands for every variable that wasn't set when --record-env was run. Without --env,
+    /// the jobs get the whole environment.
+    #[structopt(long = "env", number_of_values = 1)]
+    pub env: Vec<String>,
+    /// Record the variables currently set, so that they aren't passed with '--env _', and exit
+    #[structopt(long = "record-env")]
+    pub record_env: bool,
+    /// Print the version, the backends the jobs can be run with and the number of cores, and exit
+    #[structopt(short = "V", long)]
+    pub version: bool,
+
+    /// Run the command once, as soon as fewer than --jobs commands with the same --id run,
+    /// including the ones of other runs
+    ///
+    /// The slots are files locked with flock in the tmpdir, so that commands started separately,
+    /// like in a shell loop, are limited together. The number of jobs defaults to 1.
+    #[structopt(long = "semaphore", alias = "sem")]
+    pub semaphore: bool,
+    /// The name of the semaphore shared by the commands that limit each other
+    #[structopt(long = "id", requires = "semaphore")]
+    pub semaphore_id: Option<String>,
+    /// Wait until the commands holding the semaphore are done, instead of running a command
+    #[structopt(long = "wait", requires = "semaphore")]
+    pub semaphore_wait: bool,
+    /// Wait for the command to be done before returning, which is the default
+    #[structopt(long = "fg", requires = "semaphore")]
+    pub foreground: bool,
+    /// Return once the command holds its slot, and leave it running in the background. Its
+    /// output still goes to ours
+    #[structopt(long = "bg", requires = "semaphore", conflicts_with = "foreground")]
+    pub background: bool,
+
+    /// Run jobs on the given host over ssh, or on this machine for ':'. Can be repeated
+    ///
+    /// Each host runs as many jobs at once as --jobs, unless it is given its own number of jobs
+    /// like '4/user@host'. The commands are run by the login shell of
+    /// the remote user, with its environment.
+    #[structopt(short = "S", long = "sshlogin", number_of_values = 1)]
+    pub sshlogins: Vec<SshLogin>,
+    /// Read the hosts of --sshlogin from a file, one per line. Can be repeated
+    ///
+    /// A host can be given the number of jobs it runs at once, like '4/user@host'. Lines starting
+    /// with '#' are comments.
+    #[structopt(
+        long = "sshloginfile",
+        alias = "slf",
+        number_of_values = 1,
+        parse(from_os_str)
+    )]
+    pub sshloginfiles: Vec<PathBuf>,
+    /// Copy the argument of each job run on another host to it first, as a file
+    #[structopt(long = "transfer")]
+    pub transfer: bool,
+    /// Copy the given file back from the host once the job is done. Can be repeated
+    ///
+    /// The file can contain the same tokens as the command, like '{.}.out'. The job fails if the
+    /// file can't be copied.
+    #[structopt(long = "return", number_of_values = 1)]
+    pub returns: Vec<String>,
+    /// Remove the files copied by --transfer and --return from the host once the job is done
+    #[structopt(long = "cleanup")]
+    pub cleanup: bool,
+    /// Run every job on each of the hosts given with --sshlogin, instead of spreading the jobs
+    /// over them
+    ///
+    /// Without sshlogins, each job is run the number of times given with --copies. The copies
+    /// are separate jobs, with their own sequence numbers.
+    #[structopt(long = "onall", conflicts_with = "round_robin")]
+    pub onall: bool,
+    /// How many times --onall runs each job on this machine
+    #[structopt(long = "copies", requires = "onall")]
+    pub copies: Option<usize>,
+
+    /// Stop running jobs after some of them failed
+    ///
+    /// 'now,fail=n' kills the running jobs and the processes they started once n jobs failed,
+    /// 'soon,fail=n' waits for them but doesn't start new jobs. 'fail=n%' stops once n% of the
+    /// completed jobs failed, after at least 3 jobs completed. The default is 'never'. Either way,
+    /// the jobs that were running are recorded in the joblog once they are done or killed.
+    #[structopt(long = "halt", default_value = "never")]
+    pub halt: Halt,
+    /// What to do on Ctrl-C (SIGINT) or SIGTERM
+    ///
+    /// 'kill' passes the signal on to the running jobs and the processes they started, 'drain'
+    /// doesn't start new jobs but lets the running ones finish and 'wait' runs every job left. Each
+    /// new interrupt moves on to the next of wait, drain and kill, and exits right away after kill.
+    /// The jobs still running a second after kill are sent SIGKILL. The jobs are run in their own
+    /// process groups, so that a Ctrl-C on the terminal doesn't reach them, except with
+    /// --keep-stdin on a terminal, which only kill can be used with.
+    #[structopt(long = "on-interrupt", default_value = "kill")]
+    pub on_interrupt: Interrupt,
+    /// The shell running the commands: 'ion', 'sh', 'bash' or any shell accepting '-c', or 'none'
+    /// to run the commands without a shell
+    ///
+    /// Ion is embedded when the output isn't captured and the jobs keep stdin, the other shells are
+    /// always run as external processes and must be in the PATH. The processes started by the
+    /// embedded Ion aren't tracked as jobs, so they aren't passed the signals of --on-interrupt,
+    /// --halt now or --total-timeout, and only a Ctrl-C on the terminal can reach them.
+    ///
+    /// Without a shell, the command is split on whitespace before its tokens are replaced, so each
+    /// argument is passed as is to the program even if it contains whitespace or characters like
+    /// ';'. There is no globbing, no pipes and no redirections then.
+    #[structopt(long = "shell", default_value = "ion")]
+    pub shell: Backend,
+    /// Run the commands without a shell, same as --shell none
+    #[structopt(long = "exec")]
+    pub exec: bool,
+    /// Halt on error in a command, same as --halt now,fail=1
+    #[structopt(long = "halt-on-error")]
+    pub halt_on_error: bool,
+
+    /// Ask the user before running each command
+    ///
+    /// The answers are read from the terminal, so the arguments can still come from stdin. The run
+    /// fails without a terminal, and once it is closed the commands left are skipped and the run
+    /// fails.
+    #[structopt(short, long)]
+    pub interactive: bool,
+
+    /// Start n jobs in parallel. Defaults to the number of cores available. 0 indicates to run one
+    /// thread per job
+    ///
+    /// Also accepts a percentage of the cores (e.g. '50%') or a number of jobs to add to or
+    /// remove from the number of cores (e.g. '+2' or '-1'). These always start at least one job.
+    ///
+    /// While the jobs run, SIGUSR2 runs one more job at once and SIGUSR1 one less, down to one.
+    /// The number of jobs is fixed with 0, --sshlogin or --round-robin. SIGUSR1 also prints the
+    /// running jobs to stderr, with their slot and for how long they have been running.
+    ///
+    /// 'auto' is experimental: it starts with the number of cores, then measures how many jobs
+    /// are done per second every 2 seconds and runs one job more or less at once, towards the
+    /// highest throughput, up to 4 times the number of cores. The number of jobs is held until at
+    /// least 3 jobs are done, so that slow jobs are measured over a longer time.
+    #[structopt(short, long, allow_hyphen_values = true)]
+    pub jobs: Option<Jobs>,
+
+    /// Use n arguments per command
+    ///
+    /// The arguments are joined with spaces for '{}' and the number of arguments in the last
+    /// command may be lower than n. With --colsep, the columns of all the arguments follow each
+    /// other.
+    #[structopt(short = "n", long = "max-args")]
+    pub max_args: Option<usize>,
+
+    /// Use n lines of input per command, like -n but line by line
+    ///
+    /// A line ending with a space or a tab goes on with the next line, and empty lines are
+    /// skipped. Can't be used along with -n.
+    #[structopt(short = "L", long = "max-lines", conflicts_with = "max_args")]
+    pub max_lines: Option<usize>,
+
+    /// Put as many arguments in each command as fit in n characters, like xargs
+    ///
+    /// The length is the one of the command once its tokens are replaced. When -n is also given,
+    /// the commands have at most its number of arguments. By default, the commands are limited to
+    /// a safe fraction of the system limit and an argument that can't fit alone is an error.
+    #[structopt(short = "s", long = "max-chars")]
+    pub max_chars: Option<usize>,
+
+    /// Use the given string instead of '{}' for the argument in the command
+    #[structopt(short = "I", long = "replace-str", default_value = "{}")]
+    pub replace: String,
+    /// Add the replacement strings of GNU parallel's --plus
+    ///
+    /// '{+/}' is the directory of the argument and '{+.}' its extension, both empty when there
+    /// is none. '{..}' and '{...}' remove up to two and three extensions. '{#string}' removes the
+    /// given prefix from the argument and '{%string}' the given suffix.
+    #[structopt(long = "plus")]
+    pub plus: bool,
+    /// Define a token replaced with the value of an Ion expression. Can be repeated
+    ///
+    /// The rule is '{token} expression', where the argument is in '$arg'. For instance,
+    /// '--rpl "{upper} $to_uppercase($arg)"' makes '{upper}' the argument in upper case.
+    #[structopt(long = "rpl", number_of_values = 1)]
+    pub rules: Vec<Rule>,
+    /// Quote the values replacing the tokens, so that the shell sees each argument as a single
+    /// literal word instead of parsing it
+    ///
+    /// Only the commands run by a shell are quoted, as --exec already passes each argument as a
+    /// word. The short '-q' of GNU parallel is --quiet here.
+    #[structopt(long = "quote")]
+    pub quote: bool,
+    /// Print each argument quoted for a POSIX shell, one per line, instead of running a command
+    ///
+    /// The arguments are read like those of the jobs, and their columns are quoted one by one.
+    #[structopt(long = "shellquote")]
+    pub shellquote: bool,
+    /// Don't warn about the pitfalls found in the command before running it, such as '{}' between
+    /// single quotes
+    #[structopt(long = "no-warn")]
+    pub no_warn: bool,
+
+    /// Split stdin in blocks given to the commands on their stdin, instead of reading arguments
+    ///
+    /// The blocks are only split between two records, see --recend and --recstart. The commands are
+    /// run with an external ion process, which must be in the PATH.
+    #[structopt(long = "pipe")]
+    pub pipe: bool,
+    /// The size of the blocks in pipe mode (e.g. 512k or 1M)
+    #[structopt(
+        long = "block",
+        default_value = "1M",
+        parse(try_from_str = "parse_size")
+    )]
+    pub block: usize,
+    /// The string ending the records in pipe mode. '\n', '\t' and '\0' can be used
+    #[structopt(long = "recend", default_value = "\\n")]
+    pub recend: String,
+    /// The string starting the records in pipe mode. '\n', '\t' and '\0' can be used
+    #[structopt(long = "recstart", default_value = "")]
+    pub recstart: String,
+    /// With --pipe, start the command once per job slot and write the blocks to the stdin of the
+    /// running commands
+    ///
+    /// Each block goes to whichever command is ready first. The command is run with an empty
+    /// argument, and a single result is recorded per command once stdin is closed. A command that
+    /// stops reading its stdin fails, and the next blocks go to the other commands. The output of
+    /// the commands isn't grouped.
+    #[structopt(long = "round-robin")]
+    pub round_robin: bool,
+    /// Write the arguments of each job to the stdin of its command, instead of only replacing the
+    /// tokens with them
+    ///
+    /// Unlike --pipe, the command is still run once per input, and each argument is a line of its
+    /// stdin, or ends with NUL with --null.
+    #[structopt(
+        long = "stdin-input",
+        conflicts_with = "pipe",
+        conflicts_with = "keep_stdin"
+    )]
+    pub stdin_input: bool,
+    /// Let the jobs read the stdin of parallelion, instead of /dev/null
+    ///
+    /// Without --pipe, the jobs would otherwise share it with the arguments read from stdin. On a
+    /// terminal, the jobs stay in its process group rather than their own, so that they can read
+    /// it: a Ctrl-C on the terminal reaches them, and the processes they start aren't killed with
+    /// them.
+    #[structopt(long = "keep-stdin")]
+    pub keep_stdin: bool,
+    /// Where to create the temporary files, like the spooled output of the jobs. Defaults to
+    /// $TMPDIR, or /tmp
+    #[structopt(long = "tmpdir", parse(from_os_str))]
+    pub tmpdir: Option<PathBuf>,
+    /// Save the output of each job to a file in --tmpdir and print its path instead
+    ///
+    /// The files are left for the next command to read and remove. The errors of the jobs are
+    /// still written to stderr.
+    #[structopt(long = "files")]
+    pub files: bool,
+    /// Compress the output of the jobs with gzip when it is written to files
+    ///
+    /// The grouped output stays compressed in its spool file until it is printed, which
+    /// decompresses it as it goes. With --results, the files are 'stdout.gz' and 'stderr.gz', and
+    /// the files printed by --files are compressed.
+    #[structopt(long = "compress")]
+    pub compress: bool,
+
+    /// Split each argument in columns on the given regex
+    ///
+    /// The columns are available as '{1}', '{2}', ... in the command, and '{-1}' is the last one.
+    /// '{2..4}', '{2..}' and '{..3}' are ranges of columns joined with spaces. Quotes are not
+    /// interpreted, so a quoted field that contains the separator is split as well.
+    #[structopt(long = "colsep")]
+    pub colsep: Option<Regex>,
+    /// Read the names of the columns from the header of the input, which isn't run
+    ///
+    /// ':' makes the first line the header, a regex makes it every line up to the first one
+    /// matching it. Its last line is split like the arguments, on a tab without --colsep, and
+    /// each column can be used as '{name}' in the command. Can't be used with ':::' or --pipe.
+    #[structopt(long = "header")]
+    pub header: Option<Header>,
+
+    /// Run the jobs in a random order
+    ///
+    /// When the arguments are read from stdin or an argfile, they are all read before the first job
+    /// starts.
+    #[structopt(long = "shuf")]
+    pub shuf: bool,
+    /// The seed used by --shuf and --jitter, to get the same order and delays every time
+    #[structopt(long = "seed")]
+    pub seed: Option<u64>,
+
+    /// Each line of the argfile will be treated as a replacement on the input
+    ///
+    /// Several argfiles are read one after the other. With --link or with ':::' sources, they are
+    /// combined like the files given after '::::' instead.
+    #[structopt(
+        short = "a",
+        long = "arg-file",
+        parse(from_os_str),
+        number_of_values = 1
+    )]
+    pub argfiles: Vec<PathBuf>,
+
+    /// The arguments read from stdin or the argfiles are separated by NUL bytes instead of
+    /// newlines, like the output of 'find -print0'
+    #[structopt(short = "0", long = "null")]
+    pub null: bool,
+    /// End the commands printed by --dry-run and the paths printed by --files with a NUL byte
+    /// instead of a newline, for 'xargs -0' or 'parallelion -0'
+    ///
+    /// The output of the jobs is written as is.
+    #[structopt(long = "null-output")]
+    pub null_output: bool,
+    /// Don't run the jobs for the arguments that are empty or only whitespace
+    ///
+    /// Otherwise, an empty line of stdin or of an argfile is an empty argument, and its job is
+    /// run with '{}' replaced by nothing. With --colsep, a line is only skipped when all of its
+    /// columns are empty.
+    #[structopt(short = "r", long = "no-run-if-empty")]
+    pub no_run_if_empty: bool,
+    /// Ignore the arguments read from stdin or the argfiles that start with '#'
+    #[structopt(long = "skip-comments")]
+    pub skip_comments: bool,
+
+    /// Remove the whitespace around the arguments: 'n' for none, 'l' on the left, 'r' on the right
+    /// or 'lr' on both sides
+    ///
+    /// With --colsep, each column is trimmed.
+    #[structopt(long = "trim", default_value = "n")]
+    pub trim: Trim,
+
+    /// Take the n-th argument of every source together, instead of every combination of them
+    ///
+    /// The jobs stop with the shortest source.
+    #[structopt(long = "link")]
+    pub link: bool,
+
+    // Positionals
+    /// The command to run. '{}' tokens will be replaced with the list of arguments
+    ///
+    /// '{.}', '{/}', '{//}' and '{/.}' are replaced with the argument without its extension, its
+    /// basename, its dirname and its basename without the extension. '{#}' is replaced with the
+    /// sequence number of the job and '{%}' with the slot of the worker running it. Use '{{}}' for a
+    /// literal '{}'.
+    #[structopt(raw(
+        required_unless_one = r#"&["record_env", "semaphore_wait", "shellquote", "version"]"#
+    ))]
+    pub command: Option<String>,
+    /// The list of arguments
+    ///
+    /// The arguments can also be given after ':::', or read from the files given after '::::'.
+    /// With several ':::' or '::::' sources, a job is run for each combination of one argument of
+    /// every source, available as '{1}', '{2}', ... The words before the first source all belong
+    /// to the command. An empty argument runs a job like any other, unless --no-run-if-empty is
+    /// given.
+    pub arguments: Vec<String>,
+}
+
+/// Split the command line at the first `:::` or `::::`, between the arguments parsed by
+/// structopt and the sources of arguments
+pub fn split_sources(args: Vec<OsString>) -> (Vec<OsString>, Vec<Source>) {
+    let start = args
+        .iter()
+        .position(|arg| arg == ":::" || arg == "::::")
+        .unwrap_or(args.len());
+    let mut args = args;
+    let rest = args.split_off(start);
+    let mut sources = Vec::new();
+    for arg in rest {
+        if arg == ":::" {
+            sources.push(Source::Inline(Vec::new()));
+        } else if arg == "::::" {
+            sources.push(Source::File(PathBuf::new()));
+        } else {
+            match sources.last_mut() {
+                Some(Source::Inline(values)) => values.push(arg.to_string_lossy().into_owned()),
+                // Every argument after '::::' is a file
+                Some(Source::File(path)) if path.as_os_str().is_empty() => *path = arg.into(),
+                _ => sources.push(Source::File(arg.into())),
+            }
+        }
+    }
+    (args, sources)
+}
+
+/// How many failures stop the run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failures {
+    Count(usize),
+    /// Percentage of the completed jobs
+    Percent(f64),
+}
+
+impl Failures {
+    pub fn reached(self, failed: usize, completed: usize) -> bool {
+        match self {
+            Failures::Count(count) => failed >= count,
+            // Like GNU parallel, wait for a few jobs to complete before using the percentage
+            Failures::Percent(percent) => {
+                completed >= 3 && failed as f64 * 100. >= percent * completed as f64
+            }
+        }
+    }
+}
+
+/// How the jobs are recorded in the joblog
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoblogFormat {
+    /// A JSON object per line
+    Json,
+    /// The tab-separated columns of GNU parallel
+    Tsv,
+}
+
+impl FromStr for JoblogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(JoblogFormat::Json),
+            "tsv" => Ok(JoblogFormat::Tsv),
+            _ => Err(format!(
+                "invalid joblog format '{}', expected json or tsv",
+                s
+            )),
+        }
+    }
+}
+
+/// How the files of --results are named
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResultsFormat {
+    /// A directory per job, named after its sequence number
+    Tree,
+    /// A file per stream and job, all in the same directory
+    Flat,
+    /// A directory per job, named after its arguments
+    ByArg,
+}
+
+impl FromStr for ResultsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tree" => Ok(ResultsFormat::Tree),
+            "flat" => Ok(ResultsFormat::Flat),
+            "by-arg" => Ok(ResultsFormat::ByArg),
+            _ => Err(format!(
+                "invalid results format '{}', expected tree, flat or by-arg",
+                s
+            )),
+        }
+    }
+}
+
+/// How the progress is shown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressFormat {
+    /// A progress bar on the terminal
+    Bar,
+    /// A JSON object per line, for other programs
+    Json,
+}
+
+impl FromStr for ProgressFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bar" => Ok(ProgressFormat::Bar),
+            "json" => Ok(ProgressFormat::Json),
+            _ => Err(format!(
+                "invalid progress format '{}', expected bar or json",
+                s
+            )),
+        }
+    }
+}
+
+/// The presets of the progress bar
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BarStyle {
+    Ascii,
+    Unicode,
+    /// The number of jobs done, without a bar
+    Minimal,
+    /// The percentage of the jobs done, without a bar
+    PercentOnly,
+}
+
+impl FromStr for BarStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ascii" => Ok(BarStyle::Ascii),
+            "unicode" => Ok(BarStyle::Unicode),
+            "minimal" => Ok(BarStyle::Minimal),
+            "percent-only" => Ok(BarStyle::PercentOnly),
+            _ => Err(format!(
+                "invalid bar style '{}', expected ascii, unicode, minimal or percent-only",
+                s
+            )),
+        }
+    }
+}
+
+/// What an interrupt does to the run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    /// Pass the signal on to the running jobs
+    Kill,
+    /// Start no more jobs, but let the running ones finish
+    Drain,
+    /// Run every job left
+    Wait,
+}
+
+impl FromStr for Interrupt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kill" => Ok(Interrupt::Kill),
+            "drain" => Ok(Interrupt::Drain),
+            "wait" => Ok(Interrupt::Wait),
+            _ => Err(format!(
+                "invalid interrupt handling '{}', expected kill, drain or wait",
+                s
+            )),
+        }
+    }
+}
+
+/// How many jobs run in parallel, possibly relative to the number of cores
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Jobs {
+    Count(usize),
+    /// Adjusted while the jobs run, starting at the number of cores
+    Auto,
+    /// Percentage of the cores
+    Percent(f64),
+    /// Added to the number of cores
+    Relative(isize),
+}
+
+impl Jobs {
+    /// The number of jobs on a machine with `cores` cores
+    pub fn resolve(self, cores: usize) -> usize {
+        match self {
+            Jobs::Count(count) => count,
+            Jobs::Auto => cores,
+            Jobs::Percent(percent) => ((cores as f64 * percent / 100.) as usize).max(1),
+            Jobs::Relative(offset) => (cores as isize + offset).max(1) as usize,
+        }
+    }
+}
+
+impl FromStr for Jobs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(Jobs::Auto);
+        }
+        if let Some(percent) = s.strip_suffix('%') {
+            return match percent.parse::<f64>() {
+                Ok(percent) if percent.is_finite() && percent >= 0. => Ok(Jobs::Percent(percent)),
+                _ => Err(format!("invalid percentage of the cores '{}'", s)),
+            };
+        }
+        if s.starts_with('+') || s.starts_with('-') {
+            return s
+                .parse()
+                .map(Jobs::Relative)
+                .map_err(|_| format!("invalid number of jobs '{}'", s));
+        }
+        s.parse()
+            .map(Jobs::Count)
+            .map_err(|_| format!("invalid number of jobs '{}'", s))
+    }
+}
+
+/// The highest load average at which jobs are started
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaxLoad {
+    Absolute(f64),
+    /// Percentage of the cores
+    Percent(f64),
+}
+
+impl MaxLoad {
+    /// The maximum load on a machine with `cores` cores
+    pub fn resolve(self, cores: usize) -> f64 {
+        match self {
+            MaxLoad::Absolute(load) => load,
+            MaxLoad::Percent(percent) => cores as f64 * percent / 100.,
+        }
+    }
+}
+
+impl FromStr for MaxLoad {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (value, percent) = match s.strip_suffix('%') {
+            Some(value) => (value, true),
+            None => (s, false),
+        };
+        match value.parse::<f64>() {
+            Ok(value) if value.is_finite() && value >= 0. && percent => Ok(MaxLoad::Percent(value)),
+            Ok(value) if value.is_finite() && value >= 0. => Ok(MaxLoad::Absolute(value)),
+            _ => Err(format!("invalid maximum load '{}'", s)),
+        }
+    }
+}
+
+/// When to stop running jobs because of failures
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Halt {
+    Never,
+    /// Kill the running jobs and exit
+    Now(Failures),
+    /// Don't start new jobs, but wait for the running ones
+    Soon(Failures),
+}
+
+impl FromStr for Halt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "never" {
+            return Ok(Halt::Never);
+        }
+        let mut parts = s.splitn(2, ',');
+        let when = parts.next().unwrap_or_default();
+        let failures = match parts.next() {
+            None => Failures::Count(1),
+            Some(condition) => {
+                let value = condition
+                    .strip_prefix("fail=")
+                    .ok_or_else(|| format!("unsupported halt condition '{}'", condition))?;
+                // Zero failures would halt before any job runs, and more than 100% would never halt
+                match value.strip_suffix('%') {
+                    Some(percent) => match percent.parse::<f64>() {
+                        Ok(percent) if percent > 0. && percent <= 100. => {
+                            Failures::Percent(percent)
+                        }
+                        _ => return Err(format!("invalid percentage '{}'", value)),
+                    },
+                    None => match value.parse() {
+                        Ok(count) if count > 0 => Failures::Count(count),
+                        _ => return Err(format!("invalid number of failures '{}'", value)),
+                    },
+                }
+            }
+        };
+        match when {
+            "now" => Ok(Halt::Now(failures)),
+            "soon" => Ok(Halt::Soon(failures)),
+            _ => Err(format!("invalid halt policy '{}'", s)),
+        }
+    }
+}
+
+/// Replace the `\n`, `\t`, `\0` and `\\` escapes of a record separator with their bytes
+pub fn unescape(s: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            out.push(byte);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'n') => out.push(b'\n'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'0') => out.push(0),
+            Some(b'\\') => out.push(b'\\'),
+            Some(other) => out.extend_from_slice(&[b'\\', other]),
+            None => out.push(b'\\'),
+        }
+    }
+    out
+}
+
+/// Parse a duration in seconds, with an optional `ms`, `s`, `m`, `h` or `d` suffix
+fn parse_duration(s: &str) -> Result<StdDuration, String> {
+    let (number, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let multiplier = match unit {
+        "ms" => 0.001,
+        "s" => 1.,
+        "m" => 60.,
+        "h" => 3600.,
+        "d" => 86400.,
+        _ => return Err(format!("unknown unit '{}' in duration '{}'", unit, s)),
+    };
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", s))?;
+    if !number.is_finite() || number < 0. {
+        return Err(format!("invalid duration '{}'", s));
+    }
+    Ok(StdDuration::from_secs_f64(number * multiplier))
+}
+
+/// Parse a duration like `parse_duration`, which must not be 0
+fn parse_interval(s: &str) -> Result<StdDuration, String> {
+    match parse_duration(s)? {
+        interval if interval == StdDuration::from_secs(0) => {
+            Err(format!("the interval '{}' must be longer than 0", s))
+        }
+        interval => Ok(interval),
+    }
+}
+
+/// Parse a factor of --retry-backoff, which must be positive
+fn parse_backoff(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(factor) if factor.is_finite() && factor > 0. => Ok(factor),
+        _ => Err(format!("invalid backoff factor '{}'", s)),
+    }
+}
+
+/// A duration, or a percentage of the median runtime of the jobs
+fn parse_timeout(s: &str) -> Result<Timeout, String> {
+    let percent = match s.strip_suffix('%') {
+        Some(percent) => percent,
+        None => return parse_duration(s).map(Timeout::Fixed),
+    };
+    match percent.trim().parse::<f64>() {
+        Ok(percent) if percent.is_finite() && percent > 0. => Ok(Timeout::Median(percent / 100.)),
+        _ => Err(format!("invalid timeout '{}'", s)),
+    }
+}
+
+fn parse_bar_chars(s: &str) -> Result<String, String> {
+    if s.chars().count() < 2 {
+        return Err("the progress bar needs at least a full and an empty character".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Parse a size in bytes, with an optional `k`, `M`, `G` or `T` suffix for powers of 1024
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&s[..i], 1_u64 << 10),
+        Some((i, 'm')) | Some((i, 'M')) => (&s[..i], 1_u64 << 20),
+        Some((i, 'g')) | Some((i, 'G')) => (&s[..i], 1_u64 << 30),
+        Some((i, 't')) | Some((i, 'T')) => (&s[..i], 1_u64 << 40),
+        _ => (s, 1_u64),
+    };
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", s))?;
+    if !number.is_finite() || number < 0. {
+        return Err(format!("invalid size '{}'", s));
+    }
+    Ok((number * multiplier as f64) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn splits_the_sources_from_the_options() {
+        let (args, sources) = split_sources(os(&[
+            "parallel", "echo", ":::", "a", "b", "::::", "file", "other",
+        ]));
+        assert_eq!(args, os(&["parallel", "echo"]));
+        assert_eq!(
+            sources,
+            vec![
+                Source::Inline(vec!["a".to_string(), "b".to_string()]),
+                Source::File("file".into()),
+                Source::File("other".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unescapes_the_record_separators() {
+        assert_eq!(unescape(r"a\n\t\0\\\x"), b"a\n\t\0\\\\x".to_vec());
+    }
+
+    #[test]
+    fn parses_durations_and_sizes() {
+        assert_eq!(parse_duration("1.5"), Ok(StdDuration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Ok(StdDuration::from_secs(120)));
+        assert!(parse_duration("3w").is_err());
+        assert_eq!(parse_interval("1ms"), Ok(StdDuration::from_millis(1)));
+        assert!(parse_interval("0s").is_err());
+        assert_eq!(parse_timeout("200%"), Ok(Timeout::Median(2.)));
+        assert_eq!(
+            parse_timeout("30s"),
+            Ok(Timeout::Fixed(StdDuration::from_secs(30)))
+        );
+        assert!(parse_timeout("0%").is_err());
+        assert_eq!(parse_size("1k"), Ok(1024));
+        assert_eq!(parse_size("2M"), Ok(2 << 20));
+        assert!(parse_size("-1").is_err());
+    }
+
+    #[test]
+    fn resolves_the_number_of_jobs() {
+        let jobs = |s: &str| s.parse::<Jobs>().map(|jobs| jobs.resolve(8));
+        assert_eq!(jobs("50%"), Ok(4));
+        assert_eq!(jobs("+2"), Ok(10));
+        assert_eq!(jobs("-1"), Ok(7));
+        assert_eq!(jobs("0"), Ok(0));
+        assert_eq!(jobs("-20"), Ok(1));
+        assert_eq!(jobs("1%"), Ok(1));
+        assert_eq!(jobs("auto"), Ok(8));
+        assert!(jobs("many").is_err());
+    }
+
+    #[test]
+    fn resolves_the_maximum_load() {
+        let load = |s: &str| s.parse::<MaxLoad>().map(|load| load.resolve(8));
+        assert_eq!(load("2.5"), Ok(2.5));
+        assert_eq!(load("50%"), Ok(4.));
+        assert!(load("-1").is_err());
+        assert!(load("high").is_err());
+    }
+
+    #[test]
+    fn parses_the_halt_policy() {
+        assert_eq!("never".parse(), Ok(Halt::Never));
+        assert_eq!("now,fail=2".parse(), Ok(Halt::Now(Failures::Count(2))));
+        assert_eq!(
+            "soon,fail=50%".parse(),
+            Ok(Halt::Soon(Failures::Percent(50.)))
+        );
+        assert!("later".parse::<Halt>().is_err());
+        for invalid in &["0", "0%", "-5%", "101%", "nan%", "inf%"] {
+            assert!(format!("now,fail={}", invalid).parse::<Halt>().is_err());
+        }
+        assert_eq!(
+            "now,fail=100%".parse(),
+            Ok(Halt::Now(Failures::Percent(100.)))
+        );
+        assert!(Failures::Percent(50.).reached(2, 4));
+        assert!(!Failures::Percent(50.).reached(1, 2));
+    }
+}
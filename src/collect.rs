@@ -0,0 +1,514 @@
+//! The results of the jobs as they come back: recording them, writing their output, and
+//! stopping the run when it is halted, interrupted or takes too long
+
+use crate::{
+    cli::{Failures, Halt, Interrupt, JoblogFormat, Opts, ProgressFormat, ResultsFormat},
+    logging::{
+        print_record, save_output, write_joblog, write_output, write_results, write_summary, Held,
+        Spill, SummaryRow,
+    },
+    plan::{print_plan, PlanRow},
+    progress::Progress,
+};
+use crossbeam_channel::Receiver;
+use log::{error, info, warn};
+use parallelion::{remove_temp_files, Config, Job, JobResult, Runner, Template};
+use signal_hook::iterator::Signals;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration as StdDuration, Instant},
+};
+
+/// Holds back the results completed out of order until all the previous ones are done
+#[derive(Debug)]
+pub struct Reorder<T> {
+    /// The job whose result is released next
+    next: usize,
+    /// `None` for the jobs that won't run and must not be waited for
+    pending: BTreeMap<usize, Option<T>>,
+    /// How many results are held back
+    held: usize,
+}
+
+impl<T> Reorder<T> {
+    pub fn new() -> Self {
+        Reorder {
+            next: 0,
+            pending: BTreeMap::new(),
+            held: 0,
+        }
+    }
+
+    /// How many results are held back, waiting for a previous one
+    pub fn held(&self) -> usize {
+        self.held
+    }
+
+    /// Whether the result of the job `index` would be released right away
+    pub fn is_next(&self, index: usize) -> bool {
+        index == self.next
+    }
+
+    /// Don't wait for the job `index`, and return the results that can now be released
+    pub fn skip(&mut self, index: usize) -> Vec<T> {
+        if index >= self.next {
+            self.pending.insert(index, None);
+        }
+        self.release()
+    }
+
+    /// Add the result for the job `index` and return the results that can now be released
+    pub fn push(&mut self, index: usize, item: T) -> Vec<T> {
+        self.pending.insert(index, Some(item));
+        self.held += 1;
+        self.release()
+    }
+
+    /// Take the results from the next one up to the first missing one
+    fn release(&mut self) -> Vec<T> {
+        let mut ready = Vec::new();
+        while let Some(item) = self.pending.remove(&self.next) {
+            ready.extend(item);
+            self.next += 1;
+        }
+        self.held -= ready.len();
+        ready
+    }
+
+    /// Release all the results held back, in order, without waiting for the missing ones
+    pub fn drain(&mut self) -> Vec<T> {
+        self.held = 0;
+        std::mem::take(&mut self.pending)
+            .into_values()
+            .flatten()
+            .collect()
+    }
+}
+
+/// The exit code when more jobs failed than can be counted in it
+const TOO_MANY_FAILURES: usize = 101;
+
+/// The exit code of the run: the number of failed jobs, like GNU parallel
+fn exit_code(failed: usize) -> i32 {
+    failed.min(TOO_MANY_FAILURES) as i32
+}
+
+/// The exit code when the run took longer than --total-timeout, like timeout(1)
+const TOTAL_TIMEOUT: i32 = 124;
+
+/// How long to wait for the running jobs to stop after they were sent a signal, before sending
+/// them SIGKILL and then exiting anyway
+const GRACE_PERIOD: StdDuration = StdDuration::from_secs(1);
+
+/// Stop starting jobs and send `signal` to the process groups of the running ones, and return
+/// when to send them SIGKILL if they are still running
+fn kill_jobs(runner: &Runner, stop: &AtomicBool, signal: libc::c_int) -> Receiver<Instant> {
+    stop.store(true, Ordering::SeqCst);
+    runner.children.signal(signal);
+    crossbeam_channel::after(GRACE_PERIOD)
+}
+
+/// Send the SIGINT and SIGTERM received on the returned channel, for the main thread to handle
+/// them as given by --on-interrupt
+fn handle_signals() -> Receiver<libc::c_int> {
+    let signals = match Signals::new(&[signal_hook::SIGINT, signal_hook::SIGTERM]) {
+        Err(err) => {
+            warn!("Could not install the signal handlers: {}", err);
+            return crossbeam_channel::never();
+        }
+        Ok(signals) => signals,
+    };
+    let (tx, rx) = crossbeam_channel::unbounded();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if tx.send(signal).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Where each result goes: the joblog, the summary, --results, --files or stdout, and the
+/// failures counted towards --halt
+pub struct Recorder {
+    joblog: Option<BufWriter<File>>,
+    joblog_format: JoblogFormat,
+    summary_csv: Option<PathBuf>,
+    summary: Vec<SummaryRow>,
+    results: Option<PathBuf>,
+    results_format: ResultsFormat,
+    /// Where the output is saved with --files
+    files: Option<PathBuf>,
+    compress: bool,
+    dry_run: bool,
+    /// The template the tokens of --plan are expanded with, and the rows planned
+    plan: Option<(Arc<Template>, Vec<PlanRow>)>,
+    remote: bool,
+    json: bool,
+    null_output: bool,
+    halt: Halt,
+    /// The number of jobs given with --total-jobs
+    hint: Option<usize>,
+    completed: usize,
+    failed: usize,
+    /// The failed and the completed jobs when the run was halted
+    halted: Option<(usize, usize)>,
+}
+
+impl Recorder {
+    pub fn new(
+        opts: &mut Opts,
+        joblog: Option<BufWriter<File>>,
+        config: &Config,
+        template: &Arc<Template>,
+    ) -> Self {
+        Recorder {
+            joblog,
+            joblog_format: opts.joblog_format,
+            summary_csv: opts.summary_csv.take(),
+            summary: Vec::new(),
+            results: opts.results.take().filter(|_| !opts.dry_run),
+            results_format: opts.results_format.unwrap_or(ResultsFormat::Tree),
+            files: Some(config.tmpdir.clone()).filter(|_| opts.files && !opts.dry_run),
+            compress: config.compress,
+            dry_run: opts.dry_run,
+            plan: Some((template.clone(), Vec::new())).filter(|_| opts.plan),
+            remote: !config.sshlogins.is_empty(),
+            json: opts.progress_format == ProgressFormat::Json,
+            null_output: opts.null_output,
+            halt: if opts.halt_on_error {
+                Halt::Now(Failures::Count(1))
+            } else {
+                opts.halt
+            },
+            hint: opts.total_jobs,
+            completed: 0,
+            failed: 0,
+            halted: None,
+        }
+    }
+
+    /// How many jobs failed
+    pub fn failed(&self) -> usize {
+        self.failed
+    }
+
+    /// Record a result, and return the halt to apply when it reaches the condition of --halt
+    /// and the run isn't `stopped` yet
+    pub fn record(&mut self, result: JobResult, stopped: bool) -> Option<Halt> {
+        self.completed += 1;
+        if self.hint.is_some_and(|hint| self.completed == hint + 1) {
+            warn!(
+                "There are more jobs than the {} given with --total-jobs",
+                self.completed - 1
+            );
+        }
+        if let Some(joblog) = &mut self.joblog {
+            write_joblog(joblog, &result, self.joblog_format);
+        }
+        if self.summary_csv.is_some() {
+            self.summary.push(SummaryRow::from(&result));
+        }
+        if let Some(dir) = &self.results {
+            if let Err(err) = write_results(dir, &result, self.compress, self.results_format) {
+                error!(
+                    "Could not write the results of job {} to '{}': {}",
+                    result.seq,
+                    dir.to_string_lossy(),
+                    err
+                );
+            }
+        }
+        match &self.files {
+            Some(tmpdir) => match save_output(tmpdir, &result.output, self.compress) {
+                Ok(path) => print_record(&path.to_string_lossy(), self.null_output),
+                Err(err) => error!(
+                    "Could not save the output of job {} to '{}': {}",
+                    result.seq,
+                    tmpdir.display(),
+                    err
+                ),
+            },
+            None => write_output(&result.output),
+        }
+        if let (true, Some((template, planned))) = (self.dry_run, &mut self.plan) {
+            let job = Job {
+                index: result.seq,
+                args: result.args.clone(),
+                input: None,
+                copy: 0,
+            };
+            // The jobs whose tokens can't be expanded were reported by the workers
+            if let Ok(tokens) = template.tokens(&job, Some(result.slot)) {
+                planned.push(PlanRow::new(&result, tokens));
+            }
+            return None;
+        }
+        if self.dry_run {
+            print_record(&result.cmd, self.null_output);
+            return None;
+        }
+        info!(
+            "'{}' took {}s in {} attempt(s)",
+            result.cmd, result.duration, result.attempts
+        );
+        if result.exit_code == 0 {
+            return None;
+        }
+        warn!(
+            "'{}' exited with status code {} after {} attempt(s)",
+            result.cmd, result.exit_code, result.attempts
+        );
+        self.failed += 1;
+        let (failed, completed) = (self.failed, self.completed);
+        match self.halt {
+            _ if stopped => return None,
+            // The killed jobs are still recorded as they come back
+            Halt::Now(failures) if failures.reached(failed, completed) => warn!(
+                "Halting now after {} failed job(s), killing the running jobs",
+                failed
+            ),
+            Halt::Soon(failures) if failures.reached(failed, completed) => warn!(
+                "Halting after {} failed job(s), waiting for the running jobs",
+                failed
+            ),
+            _ => return None,
+        }
+        self.halted = Some((failed, completed));
+        Some(self.halt)
+    }
+
+    /// Write what is only known once all the results are in: the summary, the plan, and how many
+    /// jobs completed while halting
+    pub fn finish(&mut self) {
+        if let Some(joblog) = &mut self.joblog {
+            joblog.flush().unwrap();
+        }
+        if let Some(path) = &self.summary_csv {
+            write_summary(path, &self.summary);
+        }
+        if let Some((_, planned)) = &mut self.plan {
+            print_plan(planned, self.remote, self.json);
+        }
+        if let Some((failures, done)) = self.halted {
+            warn!(
+                "Halted after {} failed job(s): {} job(s) completed before, {} while halting",
+                failures,
+                done,
+                self.completed - done
+            );
+        }
+    }
+}
+
+/// Collects the results of the jobs until they are all done, or the run is cut short
+pub struct Collector {
+    pub results: Receiver<JobResult>,
+    /// Why the jobs stopped being added before the end of the input, if they did
+    pub input_errors: Receiver<String>,
+    pub recorder: Recorder,
+    pub progress: Progress,
+    pub runner: Arc<Runner>,
+    pub stop: Arc<AtomicBool>,
+    /// The results held back for --keep-order
+    pub order: Option<Reorder<Held>>,
+    /// How many results are held back with their own output before the output of the next ones
+    /// is spilled to a single file
+    pub keep_order_buffer: Option<usize>,
+    pub spill: Spill,
+    pub on_interrupt: Interrupt,
+    pub total_timeout: Option<StdDuration>,
+}
+
+impl Collector {
+    /// Handle the results until the jobs are done, and return the exit code of the run
+    pub fn run(self) -> i32 {
+        let Collector {
+            results,
+            mut input_errors,
+            mut recorder,
+            mut progress,
+            runner,
+            stop,
+            mut order,
+            keep_order_buffer,
+            mut spill,
+            on_interrupt,
+            total_timeout,
+        } = self;
+        // Whether the run is halted now, and the running jobs must be killed
+        let mut handle =
+            |result: JobResult| match recorder.record(result, stop.load(Ordering::SeqCst)) {
+                Some(Halt::Now(_)) => true,
+                Some(_) => {
+                    stop.store(true, Ordering::SeqCst);
+                    false
+                }
+                None => false,
+            };
+        let interrupts = handle_signals();
+        let mut interrupted = None;
+        let mut on_interrupt = Some(on_interrupt);
+        let mut deadline = crossbeam_channel::never();
+        // Whether the jobs left after the grace period were sent SIGKILL
+        let mut killed = false;
+        let mut total_deadline =
+            total_timeout.map_or_else(crossbeam_channel::never, crossbeam_channel::after);
+        let mut timed_out = false;
+        let mut input_failed = false;
+        let ticks = progress.ticks.clone();
+        let declined = runner.declined.clone();
+        loop {
+            crossbeam_channel::select! {
+                recv(results) -> result => match result {
+                    Ok(result) => {
+                        progress.push(result.duration);
+                        match &mut order {
+                            Some(order) => {
+                                let seq = result.seq;
+                                // The next result due is written right away, so it isn't spilled
+                                let full = |buffer| order.held() >= buffer && !order.is_next(seq);
+                                let held = match keep_order_buffer {
+                                    Some(buffer) if full(buffer) => spill.hold(result),
+                                    _ => Held::from(result),
+                                };
+                                let ready = order.push(seq, held);
+                                for result in ready.into_iter().map(|held| spill.release(held)) {
+                                    if handle(result) {
+                                        deadline = kill_jobs(&runner, &stop, libc::SIGTERM);
+                                    }
+                                }
+                            }
+                            None => {
+                                if handle(result) {
+                                    deadline = kill_jobs(&runner, &stop, libc::SIGTERM);
+                                }
+                            }
+                        }
+                    }
+                    Err(_) => break,
+                },
+                recv(declined) -> seq => {
+                    if let (Ok(seq), Some(order)) = (seq, &mut order) {
+                        for result in order.skip(seq).into_iter().map(|held| spill.release(held)) {
+                            if handle(result) {
+                                deadline = kill_jobs(&runner, &stop, libc::SIGTERM);
+                            }
+                        }
+                    }
+                },
+                recv(input_errors) -> err => match err {
+                    // The jobs already added still run
+                    Ok(err) => {
+                        error!("{}", err);
+                        stop.store(true, Ordering::SeqCst);
+                        input_failed = true;
+                    }
+                    Err(_) => input_errors = crossbeam_channel::never(),
+                },
+                recv(interrupts) -> signal => {
+                    interrupted = signal.ok();
+                    // Each interrupt is handled more harshly than the previous one
+                    match on_interrupt {
+                        Some(Interrupt::Wait) => {
+                            warn!("Interrupted, waiting for the jobs left");
+                            on_interrupt = Some(Interrupt::Drain);
+                        }
+                        Some(Interrupt::Drain) => {
+                            warn!("Interrupted, waiting for the running jobs");
+                            stop.store(true, Ordering::SeqCst);
+                            on_interrupt = Some(Interrupt::Kill);
+                        }
+                        Some(Interrupt::Kill) => {
+                            warn!("Interrupted, waiting for the running jobs to stop");
+                            let signal = interrupted.unwrap_or(libc::SIGTERM);
+                            deadline = kill_jobs(&runner, &stop, signal);
+                            on_interrupt = None;
+                        }
+                        None => {
+                            warn!("Interrupted again, exiting now");
+                            runner.children.signal(libc::SIGKILL);
+                            break;
+                        }
+                    }
+                },
+                recv(total_deadline) -> _ => {
+                    warn!("The run took longer than the total timeout, killing the running jobs");
+                    timed_out = true;
+                    total_deadline = crossbeam_channel::never();
+                    deadline = kill_jobs(&runner, &stop, libc::SIGTERM);
+                },
+                recv(deadline) -> _ => {
+                    if killed {
+                        warn!("Some jobs are still running, exiting anyway");
+                        break;
+                    }
+                    warn!("Some jobs are still running, killing them");
+                    runner.children.signal(libc::SIGKILL);
+                    killed = true;
+                    deadline = crossbeam_channel::after(GRACE_PERIOD);
+                },
+                recv(ticks) -> _ => progress.tick(),
+            }
+        }
+        // Some jobs are missing if the run was halted or interrupted
+        if let Some(order) = &mut order {
+            for result in order.drain().into_iter().map(|held| spill.release(held)) {
+                handle(result);
+            }
+        }
+        recorder.finish();
+        if let Some(signal) = interrupted {
+            progress.finish(Some("interrupted"));
+            // The jobs still running hold their temporary files
+            remove_temp_files();
+            return 128 + signal;
+        }
+        if timed_out {
+            progress.finish(Some("timed out"));
+            remove_temp_files();
+            return TOTAL_TIMEOUT;
+        }
+        // The commands skipped once the terminal was closed never ran
+        if input_failed || runner.refused() {
+            progress.finish(Some("stopped"));
+            return exit_code(recorder.failed()).max(1);
+        }
+        progress.finish(None);
+        exit_code(recorder.failed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn releases_the_results_in_order() {
+        let mut order = Reorder::new();
+        order.skip(1);
+        assert!(order.push(2, "c").is_empty());
+        assert_eq!(order.held(), 1);
+        assert!(order.is_next(0) && !order.is_next(2));
+        assert_eq!(order.push(0, "a"), ["a", "c"]);
+        assert_eq!(order.held(), 0);
+        assert!(order.is_next(3));
+        assert!(order.push(5, "f").is_empty());
+        assert!(order.push(4, "e").is_empty());
+        assert!(order.push(7, "h").is_empty());
+        assert_eq!(order.held(), 3);
+        assert_eq!(order.skip(3), ["e", "f"]);
+        assert_eq!(order.held(), 1);
+        assert_eq!(order.drain(), ["h"]);
+        assert_eq!(order.held(), 0);
+    }
+}
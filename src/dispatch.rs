@@ -0,0 +1,678 @@
+//! Reading the arguments and sending the jobs to the workers
+
+use crate::{template::Template, Job};
+use crossbeam_channel::Sender;
+use log::{debug, error};
+use rand::{rngs::StdRng, seq::SliceRandom};
+use regex::Regex;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// A source of arguments given after the options
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// The arguments following `:::`
+    Inline(Vec<String>),
+    /// The file following `::::`, whose lines are the arguments. `-` is stdin
+    File(PathBuf),
+}
+
+/// Every combination of one value of each source, the last source changing the fastest
+#[derive(Debug)]
+struct Product {
+    sources: Vec<Vec<String>>,
+    /// The position in each source of the next combination
+    indices: Vec<usize>,
+    done: bool,
+}
+
+impl Product {
+    fn new(sources: Vec<Vec<String>>) -> Self {
+        Product {
+            done: sources.is_empty() || sources.iter().any(Vec::is_empty),
+            indices: vec![0; sources.len()],
+            sources,
+        }
+    }
+}
+
+impl Iterator for Product {
+    type Item = Vec<String>;
+
+    fn next(&mut self) -> Option<Vec<String>> {
+        if self.done {
+            return None;
+        }
+        let row = self
+            .indices
+            .iter()
+            .zip(&self.sources)
+            .map(|(&i, source)| source[i].clone())
+            .collect();
+        self.done = true;
+        for (i, source) in self.indices.iter_mut().zip(&self.sources).rev() {
+            *i += 1;
+            if *i < source.len() {
+                self.done = false;
+                break;
+            }
+            *i = 0;
+        }
+        Some(row)
+    }
+}
+
+/// Which whitespace to remove around the arguments
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trim {
+    None,
+    Left,
+    Right,
+    Both,
+}
+
+impl Trim {
+    fn apply(self, arg: String) -> String {
+        match self {
+            Trim::None => arg,
+            Trim::Left => arg.trim_start().to_string(),
+            Trim::Right => arg.trim_end().to_string(),
+            Trim::Both => arg.trim().to_string(),
+        }
+    }
+}
+
+impl FromStr for Trim {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "n" => Ok(Trim::None),
+            "l" => Ok(Trim::Left),
+            "r" => Ok(Trim::Right),
+            "lr" | "rl" => Ok(Trim::Both),
+            _ => Err(format!("invalid trim mode '{}', expected n, l, r or lr", s)),
+        }
+    }
+}
+
+/// Where the header of the input ends, with --header
+#[derive(Debug, Clone)]
+pub enum Header {
+    /// After the first line, given as `:`
+    FirstLine,
+    /// After the first line matching the regex
+    Until(Regex),
+}
+
+impl FromStr for Header {
+    type Err = regex::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            ":" => Ok(Header::FirstLine),
+            _ => Regex::new(s).map(Header::Until),
+        }
+    }
+}
+
+/// Where the arguments of the jobs come from, and how they are grouped
+#[derive(Debug)]
+pub struct Inputs {
+    pub arguments: Vec<String>,
+    pub argfiles: Vec<PathBuf>,
+    /// The sources given with `:::` and `::::`, used instead of the other arguments
+    pub sources: Vec<Source>,
+    /// Zip the sources instead of combining them
+    pub link: bool,
+    pub format: Format,
+    /// Skip the arguments that are empty or only whitespace
+    pub no_run_if_empty: bool,
+    /// The whitespace removed around each argument, or each column with --colsep
+    pub trim: Trim,
+    pub colsep: Option<Regex>,
+    /// How many lines of the argfiles were already read as the header
+    pub header_lines: usize,
+    /// How many inputs are given to each job
+    pub max_args: usize,
+    /// The inputs are lines that go on when they end with a blank, and empty lines are skipped
+    pub continued_lines: bool,
+    /// The longest command, once its tokens are replaced
+    pub max_chars: usize,
+    /// Shuffle the inputs before grouping them
+    pub shuffle: Option<StdRng>,
+    /// How to split stdin in blocks, instead of reading arguments from it
+    pub pipe: Option<Pipe>,
+    /// How many jobs are run for each input, one after the other
+    pub copies: usize,
+    /// Write the arguments of each job to the stdin of its command, one record each
+    pub stdin_input: bool,
+}
+
+/// How stdin is split in blocks with --pipe
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipe {
+    /// The size after which a block is cut, at the next record boundary
+    pub block: usize,
+    pub recend: Vec<u8>,
+    pub recstart: Vec<u8>,
+}
+
+/// Splits its input in blocks of about `block` bytes, only between two records
+///
+/// A block is cut where a `recend` is followed by a `recstart`, at the last such boundary in the
+/// first `block` bytes. A record bigger than a block is given whole to a single job.
+struct Blocks<R> {
+    reader: R,
+    pipe: Pipe,
+    /// The input read but not given to a job yet
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Blocks<R> {
+    fn new(reader: R, pipe: Pipe) -> Self {
+        Blocks {
+            reader,
+            pipe,
+            buffer: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// Read until the buffer holds `size` bytes or the input ends
+    fn fill(&mut self, size: usize) {
+        if self.eof || self.buffer.len() >= size {
+            return;
+        }
+        let wanted = (size - self.buffer.len()) as u64;
+        let read = (&mut self.reader)
+            .take(wanted)
+            .read_to_end(&mut self.buffer)
+            .expect("Could not read stdin");
+        if (read as u64) < wanted {
+            self.eof = true;
+        }
+    }
+
+    /// Whether the buffer can be cut before `pos`
+    fn is_boundary(&self, pos: usize) -> bool {
+        pos > 0
+            && self.buffer[..pos].ends_with(&self.pipe.recend)
+            && self.buffer[pos..].starts_with(&self.pipe.recstart)
+    }
+}
+
+impl<R: Read> Iterator for Blocks<R> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let block = self.pipe.block.max(1);
+        let mut size = block;
+        loop {
+            // Read a bit more so that a record starting right after the block can be seen
+            self.fill(size + self.pipe.recstart.len());
+            if self.buffer.is_empty() {
+                return None;
+            }
+            if self.eof && self.buffer.len() <= block {
+                return Some(std::mem::take(&mut self.buffer));
+            }
+            let limit = block.min(self.buffer.len());
+            let cut = (1..=limit)
+                .rev()
+                .find(|&pos| self.is_boundary(pos))
+                .or_else(|| (limit + 1..=self.buffer.len()).find(|&pos| self.is_boundary(pos)));
+            match cut {
+                Some(pos) => {
+                    let rest = self.buffer.split_off(pos);
+                    return Some(std::mem::replace(&mut self.buffer, rest));
+                }
+                None if self.eof => return Some(std::mem::take(&mut self.buffer)),
+                // The record is bigger than a block, read more of it
+                None => size += block,
+            }
+        }
+    }
+}
+
+/// How the argfiles and stdin are split in arguments
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Format {
+    /// The arguments are separated by NUL bytes instead of newlines
+    pub null: bool,
+    /// Ignore the arguments starting with `#`
+    pub skip_comments: bool,
+}
+
+/// The records of a file, separated by newlines or NUL bytes. The last one doesn't have to be
+/// terminated
+fn records<R: BufRead + 'static>(
+    reader: R,
+    format: Format,
+    error: &'static str,
+) -> Box<dyn Iterator<Item = String>> {
+    let records: Box<dyn Iterator<Item = String>> = if format.null {
+        Box::new(
+            reader
+                .split(b'\0')
+                .map(move |arg| String::from_utf8_lossy(&arg.expect(error)).into_owned()),
+        )
+    } else {
+        Box::new(reader.lines().map(move |arg| arg.expect(error)))
+    };
+    if format.skip_comments {
+        Box::new(records.filter(|arg| !arg.starts_with('#')))
+    } else {
+        records
+    }
+}
+
+/// The lines of input, taken from the inline arguments, the argfiles or stdin in that order
+///
+/// They are read one at a time as the jobs are started, so that a job starts as soon as its line
+/// arrives on stdin, like with `tail -f`.
+fn read_inputs(
+    arguments: Vec<String>,
+    argfiles: Vec<PathBuf>,
+    format: Format,
+) -> Box<dyn Iterator<Item = String>> {
+    if !arguments.is_empty() {
+        return Box::new(arguments.into_iter());
+    }
+    if argfiles.is_empty() {
+        return records(BufReader::new(io::stdin()), format, "Could not stdin");
+    }
+    Box::new(argfiles.into_iter().flat_map(move |argfile| {
+        let file = match File::open(&argfile) {
+            Err(err) => {
+                error!(
+                    "Could not open arg file '{}' for reading: {}",
+                    argfile.to_string_lossy(),
+                    err
+                );
+                std::process::exit(1);
+            }
+            Ok(file) => file,
+        };
+        records(BufReader::new(file), format, "Could not read the file")
+    }))
+}
+
+/// Joins the lines ending with a space or a tab with the next one, like xargs -L
+struct LogicalLines<I>(I);
+
+impl<I: Iterator<Item = String>> Iterator for LogicalLines<I> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let mut line = self.0.next()?;
+        while line.ends_with(|c| c == ' ' || c == '\t') {
+            match self.0.next() {
+                Some(next) => line.push_str(&next),
+                None => break,
+            }
+        }
+        Some(line)
+    }
+}
+
+/// The first value of each source, then the second one, ... until the shortest source ends
+fn zip(sources: Vec<Vec<String>>) -> impl Iterator<Item = Vec<String>> {
+    let len = sources.iter().map(Vec::len).min().unwrap_or(0);
+    (0..len).map(move |i| sources.iter().map(|source| source[i].clone()).collect())
+}
+
+/// The arguments of a source given with `:::` or `::::`, read as they are needed
+fn source_records(source: Source, format: Format) -> Box<dyn Iterator<Item = String>> {
+    match source {
+        Source::Inline(values) => Box::new(values.into_iter()),
+        Source::File(path) if path == Path::new("-") => read_inputs(Vec::new(), Vec::new(), format),
+        Source::File(path) => read_inputs(Vec::new(), vec![path], format),
+    }
+}
+
+/// All the arguments of a source given with `:::` or `::::`
+fn read_source(source: Source, format: Format) -> Vec<String> {
+    source_records(source, format).collect()
+}
+
+/// Read the header at the start of the arguments, the argfiles or stdin, which isn't given to
+/// any job, and return the columns of its last line
+///
+/// The header lines are taken out of the inline arguments and stdin, and counted in
+/// `header_lines` for the argfiles, which are read again by [`add_jobs`].
+pub fn read_header(inputs: &mut Inputs, header: &Header) -> Vec<String> {
+    let is_end = |line: &str| match header {
+        Header::FirstLine => true,
+        Header::Until(end) => end.is_match(line),
+    };
+    let mut last = None;
+    if !inputs.arguments.is_empty() {
+        let count = inputs
+            .arguments
+            .iter()
+            .position(|line| is_end(line))
+            .map_or(inputs.arguments.len(), |i| i + 1);
+        last = inputs.arguments.drain(..count).last();
+    } else {
+        // The rest of stdin stays in its buffer for the jobs
+        let lines = if inputs.argfiles.is_empty() {
+            records(io::stdin().lock(), inputs.format, "Could not read stdin")
+        } else {
+            read_inputs(Vec::new(), inputs.argfiles.clone(), inputs.format)
+        };
+        let count_lines = !inputs.argfiles.is_empty();
+        for line in lines {
+            if count_lines {
+                inputs.header_lines += 1;
+            }
+            let end = is_end(&line);
+            last = Some(line);
+            if end {
+                break;
+            }
+        }
+    }
+    let trim = inputs.trim;
+    match (last, &inputs.colsep) {
+        (Some(line), Some(colsep)) => colsep
+            .split(&line)
+            .map(|column| trim.apply(column.to_string()))
+            .collect(),
+        (Some(line), None) => vec![trim.apply(line)],
+        (None, _) => Vec::new(),
+    }
+}
+
+/// The longest single argument Linux accepts, including its NUL byte
+const MAX_ARG_STRLEN: usize = 128 * 1024;
+
+/// The default limit of the length of the commands: half of ARG_MAX, which also counts the
+/// environment, and short enough to be a single argument, as a command is given whole to the
+/// shell
+pub fn default_max_chars() -> usize {
+    // Safe, as sysconf only reads a limit of the system
+    let arg_max = unsafe { libc::sysconf(libc::_SC_ARG_MAX) };
+    let half = if arg_max > 0 {
+        arg_max as usize / 2
+    } else {
+        usize::MAX
+    };
+    half.min(MAX_ARG_STRLEN - 1)
+}
+
+/// The length of the command run with the arguments, once its tokens are replaced
+fn command_length(template: &Template, args: &mut Vec<String>) -> usize {
+    let job = Job {
+        index: 0,
+        args: std::mem::take(args),
+        input: None,
+        copy: 0,
+    };
+    // A command that can't be expanded is reported by the worker
+    let length = template
+        .substitute(&job, None)
+        .map_or(0, |command| command.len());
+    *args = job.args;
+    length
+}
+
+/// Send the jobs to the workers, except for the ones in `skip`, until `stop` is set
+///
+/// Fails once an argument is too long for the command to fit in `max_chars`, leaving the jobs
+/// already sent to run.
+pub fn add_jobs(
+    template: Arc<Template>,
+    inputs: Inputs,
+    skip: HashSet<usize>,
+    stop: Arc<AtomicBool>,
+    tx: Sender<Job>,
+) -> Result<(), String> {
+    let Inputs {
+        arguments,
+        argfiles,
+        sources,
+        link,
+        format,
+        no_run_if_empty,
+        trim,
+        colsep,
+        header_lines,
+        max_args,
+        continued_lines,
+        max_chars,
+        shuffle,
+        pipe,
+        copies,
+        stdin_input,
+    } = inputs;
+    let terminator = if format.null { '\0' } else { '\n' };
+    let mut i = 0;
+    let mut start = |args: Vec<String>, input: Option<Vec<u8>>| {
+        let input = match input {
+            None if stdin_input => Some(
+                args.iter()
+                    .flat_map(|arg| format!("{}{}", arg, terminator).into_bytes())
+                    .collect(),
+            ),
+            input => input,
+        };
+        for copy in 0..copies {
+            if skip.contains(&i) {
+                debug!("Skipping {}: {:?}", i, args);
+                i += 1;
+                continue;
+            }
+            let job = Job {
+                index: i,
+                args: args.clone(),
+                input: input.clone(),
+                copy,
+            };
+            debug!("Starting {}: {:?}", i, job.args);
+            if tx.send(job).is_err() {
+                // Only happens when every worker is gone, with --round-robin
+                stop.store(true, Ordering::SeqCst);
+            }
+            i += 1;
+        }
+    };
+
+    if let Some(pipe) = pipe {
+        for block in Blocks::new(io::stdin(), pipe) {
+            if stop.load(Ordering::SeqCst) {
+                debug!("Stopped reading stdin");
+                return Ok(());
+            }
+            start(Vec::new(), Some(block));
+        }
+        return Ok(());
+    }
+
+    // Group `max_args` inputs per job, the columns of every input being joined together
+    let mut batch = Vec::new();
+    let mut count = 0;
+    let mut rows: Box<dyn Iterator<Item = Vec<String>>> = if sources.is_empty() {
+        let mut lines: Box<dyn Iterator<Item = String>> =
+            Box::new(read_inputs(arguments, argfiles, format).skip(header_lines));
+        if continued_lines {
+            lines = Box::new(LogicalLines(lines).filter(|line| !line.is_empty()));
+        }
+        Box::new(lines.map(move |arg| match &colsep {
+            Some(colsep) => colsep.split(&arg).map(String::from).collect(),
+            None => vec![arg],
+        }))
+    } else if sources.len() == 1 {
+        // Nothing to combine, so the source is read as the jobs go like stdin
+        let source = sources.into_iter().next().expect("There is a source");
+        Box::new(source_records(source, format).map(|arg| vec![arg]))
+    } else {
+        let sources = sources
+            .into_iter()
+            .map(|source| read_source(source, format))
+            .collect();
+        if link {
+            Box::new(zip(sources))
+        } else {
+            Box::new(Product::new(sources))
+        }
+    };
+    if let Some(mut rng) = shuffle {
+        let mut all: Vec<_> = rows.collect();
+        all.shuffle(&mut rng);
+        rows = Box::new(all.into_iter());
+    }
+    for row in rows {
+        if stop.load(Ordering::SeqCst) {
+            debug!("Stopped reading the arguments");
+            return Ok(());
+        }
+        let row: Vec<_> = row.into_iter().map(|arg| trim.apply(arg)).collect();
+        if no_run_if_empty && row.iter().all(|arg| arg.trim().is_empty()) {
+            debug!("Skipping an empty argument");
+            continue;
+        }
+        let columns = row.len();
+        batch.extend(row);
+        if count > 0 && command_length(&template, &mut batch) > max_chars {
+            // The batch is full without this input, which starts the next one
+            let row = batch.split_off(batch.len() - columns);
+            start(std::mem::replace(&mut batch, row), None);
+            count = 0;
+        }
+        if count == 0 && command_length(&template, &mut batch) > max_chars {
+            return Err(format!(
+                "An argument makes the command longer than {} characters: '{:.50}'",
+                max_chars,
+                batch.join(" ")
+            ));
+        }
+        count += 1;
+        if count == max_args {
+            start(std::mem::replace(&mut batch, Vec::new()), None);
+            count = 0;
+        }
+    }
+    if count > 0 {
+        start(batch, None);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn combines_every_value_of_the_sources() {
+        let rows: Vec<_> = Product::new(vec![strings(&["a", "b"]), strings(&["1", "2"])]).collect();
+        assert_eq!(
+            rows,
+            vec![
+                strings(&["a", "1"]),
+                strings(&["a", "2"]),
+                strings(&["b", "1"]),
+                strings(&["b", "2"]),
+            ]
+        );
+        assert_eq!(Product::new(vec![strings(&["a"]), Vec::new()]).count(), 0);
+    }
+
+    #[test]
+    fn zips_until_the_shortest_source() {
+        let rows: Vec<_> = zip(vec![strings(&["a", "b", "c"]), strings(&["1", "2"])]).collect();
+        assert_eq!(rows, vec![strings(&["a", "1"]), strings(&["b", "2"])]);
+    }
+
+    #[test]
+    fn cuts_the_blocks_between_records() {
+        let pipe = Pipe {
+            block: 4,
+            recend: b"\n".to_vec(),
+            recstart: Vec::new(),
+        };
+        let blocks: Vec<_> = Blocks::new(&b"ab\ncd\nefgh\n"[..], pipe).collect();
+        assert_eq!(
+            blocks,
+            vec![b"ab\n".to_vec(), b"cd\n".to_vec(), b"efgh\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn fills_the_commands_up_to_the_maximum_length() {
+        let template = Arc::new(Template::new("echo {}".to_string(), "{}".to_string()));
+        let arguments: Vec<_> = (0..100).map(|i| format!("arg{}", i)).collect();
+        let inputs = Inputs {
+            arguments: arguments.clone(),
+            argfiles: Vec::new(),
+            sources: Vec::new(),
+            link: false,
+            format: Format {
+                null: false,
+                skip_comments: false,
+            },
+            no_run_if_empty: false,
+            trim: Trim::None,
+            colsep: None,
+            header_lines: 0,
+            max_args: usize::MAX,
+            continued_lines: false,
+            max_chars: 40,
+            shuffle: None,
+            pipe: None,
+            copies: 1,
+            stdin_input: false,
+        };
+        let (tx, rx) = crossbeam_channel::unbounded();
+        add_jobs(
+            template.clone(),
+            inputs,
+            HashSet::new(),
+            Arc::new(AtomicBool::new(false)),
+            tx,
+        )
+        .unwrap();
+        let jobs: Vec<_> = rx.iter().collect();
+        for job in &jobs {
+            assert!(template.substitute(job, None).unwrap().len() <= 40);
+        }
+        for pair in jobs.windows(2) {
+            // The first argument of the next command didn't fit
+            let mut args = pair[0].args.clone();
+            args.push(pair[1].args[0].clone());
+            assert!(command_length(&template, &mut args) > 40);
+        }
+        let args: Vec<_> = jobs.into_iter().flat_map(|job| job.args).collect();
+        assert_eq!(args, arguments);
+    }
+
+    #[test]
+    fn lines_ending_with_a_blank_go_on() {
+        let lines = strings(&["a ", "b", "c\t", "d ", "", "e", "f "]).into_iter();
+        assert_eq!(
+            LogicalLines(lines).collect::<Vec<_>>(),
+            strings(&["a b", "c\td e", "f "])
+        );
+    }
+
+    #[test]
+    fn trims_the_arguments() {
+        assert_eq!("rl".parse(), Ok(Trim::Both));
+        assert_eq!(Trim::Left.apply("  a  ".to_string()), "a  ");
+        assert_eq!(Trim::Right.apply("  a  ".to_string()), "  a");
+    }
+}
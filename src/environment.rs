@@ -0,0 +1,82 @@
+//! The environment variables passed to the jobs with --env
+
+use log::error;
+use std::{collections::HashSet, env, fs, path::PathBuf};
+
+/// The file listing the variables recorded with --record-env, like GNU parallel
+///
+/// It holds the name of a variable on each line, in no particular order.
+fn ignored_vars_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".parallel").join("ignored_vars"))
+}
+
+/// Record the names of the variables currently set, which '--env _' doesn't pass to the jobs
+///
+/// The file is replaced at once, so that a run reading it never sees it half-written.
+pub fn record_env() {
+    let path = match ignored_vars_path() {
+        Some(path) => path,
+        None => {
+            error!("Can't record the environment without a HOME");
+            std::process::exit(1);
+        }
+    };
+    let names: String = env::vars_os()
+        .map(|(name, _)| name.to_string_lossy().into_owned() + "\n")
+        .collect();
+    let partial = path.with_extension("partial");
+    let written = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&partial, names))
+        .and_then(|_| fs::rename(&partial, &path));
+    if let Err(err) = written {
+        error!(
+            "Could not record the environment to '{}': {}",
+            path.to_string_lossy(),
+            err
+        );
+        std::process::exit(1);
+    }
+}
+
+/// The names of the variables passed to the jobs, with '_' replaced by every variable set that
+/// wasn't recorded with --record-env
+pub fn forwarded_vars(names: Vec<String>) -> Vec<String> {
+    if !names.iter().any(|name| name == "_") {
+        return names;
+    }
+    let ignored = ignored_vars_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .unwrap_or_default();
+    let set = env::vars_os().map(|(name, _)| name.to_string_lossy().into_owned());
+    let ignored = ignored
+        .lines()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect();
+    expand_names(names, set, ignored)
+}
+
+fn expand_names(
+    names: Vec<String>,
+    set: impl Iterator<Item = String>,
+    ignored: HashSet<&str>,
+) -> Vec<String> {
+    let mut expanded: Vec<String> = names.into_iter().filter(|name| name != "_").collect();
+    expanded.extend(set.filter(|name| !ignored.contains(name.as_str())));
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn underscore_stands_for_the_unrecorded_variables() {
+        let names = vec!["_".to_string(), "FOO".to_string()];
+        let set = vec!["HOME", "PATH", "MY_VAR"].into_iter().map(String::from);
+        let ignored = vec!["HOME", "PATH"].into_iter().collect();
+        assert_eq!(expand_names(names, set, ignored), vec!["FOO", "MY_VAR"]);
+    }
+}
@@ -0,0 +1,377 @@
+//! The engine of parallelion: the jobs are built from a command template and their arguments,
+//! then run by a pool of workers.
+//!
+//! [`run_jobs`] runs a list of jobs with a [`Config`] and hands their results back one by one.
+//! The command line tool has more to it (argfiles, `:::` sources, pipe mode, ...) and drives the
+//! lower-level pieces, [`add_jobs`] and [`start_workers`], itself.
+
+mod dispatch;
+mod remote;
+mod semaphore;
+mod tempfile;
+mod template;
+mod worker;
+
+pub use crate::{
+    dispatch::{
+        add_jobs, default_max_chars, read_header, Format, Header, Inputs, Pipe, Source, Trim,
+    },
+    remote::{parse_sshlogins, SshLogin},
+    semaphore::{Permit, Semaphore},
+    tempfile::{remove_temp_files, Spool, TempFile},
+    template::{Rule, Template},
+    worker::{
+        start_workers, Backend, Children, Counters, HillClimb, Pool, Runner, Running, Timeout,
+    },
+};
+use chrono::{DateTime, Duration, Local};
+use std::{
+    io::{self, Read},
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+    thread,
+    time::Duration as StdDuration,
+};
+
+/// How many jobs can wait for each worker before the producer blocks
+const JOBS_PER_WORKER: usize = 4;
+
+/// The capacity of the job queue, so that reading the input is held back by the workers instead
+/// of filling the memory. With one thread per job, there is still room for a few jobs
+pub fn queue_capacity(workers: usize) -> usize {
+    JOBS_PER_WORKER * workers.max(1)
+}
+
+/// A job waiting to be run by a worker
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    /// The position of the job in the input, starting at 0
+    pub index: usize,
+    /// The columns of the argument, or the whole argument if it isn't split
+    pub args: Vec<String>,
+    /// The data to write on the stdin of the command
+    pub input: Option<Vec<u8>>,
+    /// Which copy of its input the job runs when each one is run several times, starting at 0
+    pub copy: usize,
+}
+
+/// What a job did, sent back by the worker once it is done
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobResult {
+    /// The position of the job in the input, starting at 0
+    pub seq: usize,
+    /// The columns of the argument of the job
+    pub args: Vec<String>,
+    pub exit_code: i32,
+    pub start: DateTime<Local>,
+    pub duration: Duration,
+    pub cmd: String,
+    /// How many times the command was run
+    pub attempts: usize,
+    /// The host the job ran on, or None for this machine
+    pub host: Option<String>,
+    /// The slot of the worker that ran the job, like `{%}`
+    pub slot: usize,
+    /// The output of the last attempt, when it is grouped
+    pub output: Output,
+}
+
+/// What a job wrote, kept until it is done
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Output {
+    /// What the job wrote to stdout, when it isn't spooled
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The file the stdout was spooled to instead, as it is when it is grouped unless the file
+    /// can't be created
+    pub stdout_spool: Option<Spool>,
+    pub stderr_spool: Option<Spool>,
+}
+
+impl Output {
+    /// Read what the job wrote to stdout, from its spool if it was spooled
+    pub fn read_stdout(&self) -> io::Result<Box<dyn Read + '_>> {
+        match &self.stdout_spool {
+            Some(spool) => spool.reader(),
+            None => Ok(Box::new(&self.stdout[..])),
+        }
+    }
+
+    /// Read what the job wrote to stderr, from its spool if it was spooled
+    pub fn read_stderr(&self) -> io::Result<Box<dyn Read + '_>> {
+        match &self.stderr_spool {
+            Some(spool) => spool.reader(),
+            None => Ok(Box::new(&self.stderr[..])),
+        }
+    }
+}
+
+/// When the output of the jobs is written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    /// Once the job is done, all at once
+    Group,
+    /// As soon as the job writes a whole line
+    LineBuffer,
+    /// As soon as the job writes it
+    Ungroup,
+}
+
+/// How the jobs are run by [`run_jobs`], the options of the command line tool that aren't about
+/// reading the arguments or writing the results
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// The command run for each job, with the tokens replaced by the arguments of the job
+    pub command: String,
+    /// The string replaced with the whole argument, `{}` by default
+    pub replace: String,
+    /// How many jobs are run in parallel on each machine, or 0 to run them all at once
+    pub jobs: usize,
+    /// The names of the columns of the arguments, usable as `{name}` tokens
+    pub columns: Vec<String>,
+    /// Replace `{-1}` and the ranges of columns like `{2..4}`, when the arguments are split in
+    /// columns
+    pub column_ranges: bool,
+    /// Replace the tokens of GNU parallel's --plus as well
+    pub plus: bool,
+    /// The tokens replaced with the value of Ion expressions
+    pub rules: Vec<Rule>,
+    /// Quote the values replacing the tokens of the command for the shell
+    pub quote: bool,
+    /// The machines the jobs are run on, only this one when empty
+    pub sshlogins: Vec<SshLogin>,
+    /// Copy the argument of the jobs run on other hosts to them beforehand
+    pub transfer: bool,
+    /// The templates of the files copied back from the other hosts once their jobs are done
+    pub returns: Vec<String>,
+    /// Remove the copied files from the other hosts once their jobs are done
+    pub cleanup: bool,
+    /// Run every job on each of the machines, or `copies` times when there are no sshlogins
+    pub onall: bool,
+    /// How many times each job is run with `onall` on this machine
+    pub copies: usize,
+    pub backend: Backend,
+    pub output: OutputMode,
+    /// The template of the prefix of the output lines of each job, followed by a tab
+    pub tag: Option<String>,
+    /// Prefix the output lines with the sequence number of the job and a colon instead
+    pub number_lines: bool,
+    /// The template of the directory each job runs in
+    pub workdir: Option<String>,
+    /// The only environment variables passed to the jobs along with PATH, instead of all of them
+    pub env: Option<Vec<String>>,
+    /// How many times a failing job is run again
+    pub retries: usize,
+    /// How long to wait before the first retry of a failing job, none when zero
+    pub retry_delay: StdDuration,
+    /// How much longer to wait before each following retry
+    pub retry_backoff: f64,
+    /// The longest wait before a retry
+    pub retry_max_delay: Option<StdDuration>,
+    /// How long a job can run before being killed
+    pub timeout: Option<Timeout>,
+    /// The time between the start of two jobs
+    pub delay: Option<StdDuration>,
+    /// How much the delay varies at random, more or less
+    pub jitter: StdDuration,
+    /// The seed of the random delays, to get the same ones every time
+    pub seed: Option<u64>,
+    /// Don't start jobs while the load average of the system is above this
+    pub load: Option<f64>,
+    /// Don't start jobs while less than this many bytes of memory are available
+    pub memfree: Option<usize>,
+    /// Added to the niceness of the jobs, lowering their priority when positive
+    pub nice: Option<i32>,
+    /// Only print the commands, without running them
+    pub dry_run: bool,
+    /// Ask on the terminal before running each command
+    pub interactive: bool,
+    /// Print the commands to stderr when they start
+    pub show_commands: bool,
+    /// Feed the input of the jobs to a single long-running command per slot
+    pub round_robin: bool,
+    /// Let the jobs without an input read our stdin, instead of /dev/null
+    pub keep_stdin: bool,
+    /// Where the temporary files are created
+    pub tmpdir: PathBuf,
+    /// Compress the output spooled to the temporary files
+    pub compress: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            command: String::new(),
+            replace: "{}".to_string(),
+            jobs: num_cpus::get(),
+            columns: Vec::new(),
+            column_ranges: false,
+            plus: false,
+            rules: Vec::new(),
+            quote: false,
+            sshlogins: Vec::new(),
+            transfer: false,
+            returns: Vec::new(),
+            cleanup: false,
+            onall: false,
+            copies: 1,
+            backend: Backend::Ion,
+            output: OutputMode::Group,
+            tag: None,
+            number_lines: false,
+            workdir: None,
+            env: None,
+            retries: 0,
+            retry_delay: StdDuration::from_secs(0),
+            retry_backoff: 1.,
+            retry_max_delay: None,
+            timeout: None,
+            delay: None,
+            jitter: StdDuration::from_secs(0),
+            seed: None,
+            load: None,
+            memfree: None,
+            nice: None,
+            dry_run: false,
+            interactive: false,
+            show_commands: false,
+            round_robin: false,
+            keep_stdin: false,
+            tmpdir: std::env::temp_dir(),
+            compress: false,
+        }
+    }
+}
+
+impl Config {
+    /// A template using the replacement string and the tokens of the config, like the command
+    pub fn template(&self, text: String) -> Template {
+        Template::new(text, self.replace.clone())
+            .with_columns(self.columns.clone())
+            .with_column_ranges(self.column_ranges)
+            .with_plus(self.plus)
+            .with_rules(self.rules.clone())
+    }
+
+    /// The template of the command, whose values are quoted with `quote`
+    pub fn command_template(&self) -> Template {
+        self.template(self.command.clone()).with_quote(self.quote)
+    }
+
+    /// How many workers run the jobs, the slots of every machine
+    pub fn workers(&self) -> usize {
+        if self.sshlogins.is_empty() {
+            return self.jobs;
+        }
+        self.sshlogins
+            .iter()
+            .map(|login| login.slots(self.jobs))
+            .sum()
+    }
+
+    /// How many times each job is run: once on every machine with `onall`
+    pub fn copies(&self) -> usize {
+        match (self.onall, self.sshlogins.len()) {
+            (false, _) => 1,
+            (true, 0) => self.copies,
+            (true, logins) => logins,
+        }
+    }
+}
+
+/// Run a job for each item of `inputs`, the columns of its argument, and call `on_result` with
+/// the result of each job as soon as it is done
+///
+/// The jobs finish in any order, their `seq` is their position in `inputs`, or in the copies of
+/// `inputs` with `onall`. This returns once every job is done.
+///
+/// ```
+/// use parallelion::{run_jobs, Backend, Config};
+/// use std::io::Read;
+///
+/// let config = Config {
+///     command: "echo {1}; exit {2}".to_string(),
+///     backend: Backend::Shell("sh".to_string()),
+///     ..Config::default()
+/// };
+/// let inputs = vec![
+///     vec!["a".to_string(), "0".to_string()],
+///     vec!["b".to_string(), "3".to_string()],
+/// ];
+/// let mut results = Vec::new();
+/// run_jobs(&config, inputs, |result| results.push(result));
+///
+/// results.sort_by_key(|result| result.seq);
+/// let stdout = |seq: usize| {
+///     let mut stdout = String::new();
+///     let mut output = results[seq].output.read_stdout().unwrap();
+///     output.read_to_string(&mut stdout).unwrap();
+///     stdout
+/// };
+/// assert_eq!(results[0].exit_code, 0);
+/// assert_eq!(stdout(0), "a\n");
+/// assert_eq!(results[1].exit_code, 3);
+/// assert_eq!(stdout(1), "b\n");
+/// ```
+pub fn run_jobs<I, F>(config: &Config, inputs: I, on_result: F)
+where
+    I: IntoIterator<Item = Vec<String>>,
+    I::IntoIter: Send + 'static,
+    F: FnMut(JobResult),
+{
+    let (tx, rx) = crossbeam_channel::bounded(queue_capacity(config.workers()));
+    let (rtx, rrx) = crossbeam_channel::unbounded();
+    let template = Arc::new(config.command_template());
+    let runner = Arc::new(Runner::new(config));
+    let stop = Arc::new(AtomicBool::new(false));
+    start_workers(config.workers(), &runner, &template, &stop, rx, rtx);
+
+    let inputs = inputs.into_iter();
+    let copies = config.copies();
+    thread::spawn(move || {
+        let jobs = inputs.flat_map(|args| (0..copies).map(move |copy| (args.clone(), copy)));
+        for (index, (args, copy)) in jobs.enumerate() {
+            let job = Job {
+                index,
+                args,
+                input: None,
+                copy,
+            };
+            if tx.send(job).is_err() {
+                break;
+            }
+        }
+    });
+    rrx.iter().for_each(on_result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn the_input_is_read_as_the_jobs_are_done() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let counter = read.clone();
+        let inputs = (0..2000).map(move |i| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            vec![i.to_string()]
+        });
+        let config = Config {
+            command: "true {}".to_string(),
+            jobs: 2,
+            backend: Backend::Exec,
+            ..Config::default()
+        };
+        let (mut done, mut in_flight) = (0, 0);
+        run_jobs(&config, inputs, |_| {
+            done += 1;
+            in_flight = in_flight.max(read.load(Ordering::SeqCst) - done);
+        });
+        assert_eq!(done, 2000);
+        // The queued jobs, the running ones, the one being sent, and the results not handled yet
+        let bound = queue_capacity(2) + 2 + 1 + 2;
+        assert!(in_flight <= bound, "{} jobs in flight", in_flight);
+    }
+}
@@ -0,0 +1,683 @@
+//! The logs, the joblog and the output of the jobs
+
+use crate::cli::{JoblogFormat, Opts, ResultsFormat};
+use flate2::{write::GzEncoder, Compression};
+use log::{error, warn};
+use parallelion::{JobResult, Output, TempFile};
+use serde::{Deserialize, Serialize};
+use simplelog::*;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+/// The part of a joblog line needed to resume a previous run
+#[derive(Debug, Deserialize)]
+struct Recorded {
+    seq: usize,
+    exit_code: i32,
+}
+
+/// A single line of the joblog
+#[derive(Debug, Serialize)]
+struct LogRecord<'a> {
+    seq: usize,
+    start: String,
+    duration: f64,
+    cmd: &'a str,
+    exit_code: i32,
+    attempts: usize,
+    /// `:` for this machine, like GNU parallel
+    host: &'a str,
+}
+
+impl<'a> From<&'a JobResult> for LogRecord<'a> {
+    fn from(result: &'a JobResult) -> Self {
+        LogRecord {
+            seq: result.seq,
+            start: result.start.to_rfc3339(),
+            duration: result.duration.num_milliseconds() as f64 / 1000.,
+            cmd: &result.cmd,
+            exit_code: result.exit_code,
+            attempts: result.attempts,
+            host: result.host.as_deref().unwrap_or(":"),
+        }
+    }
+}
+
+/// The header row of the tsv joblog, the columns of GNU parallel
+const TSV_HEADER: &str =
+    "Seq\tHost\tStarttime\tJobRuntime\tSend\tReceive\tExitval\tSignal\tCommand";
+
+/// A row of the tsv joblog, where the columns parallelion doesn't track are 0
+fn tsv_record(result: &JobResult) -> String {
+    format!(
+        "{}\t{}\t{:10.3}\t{:8.3}\t0\t0\t{}\t0\t{}",
+        result.seq + 1,
+        result.host.as_deref().unwrap_or(":"),
+        result.start.timestamp_millis() as f64 / 1000.,
+        result.duration.num_milliseconds() as f64 / 1000.,
+        result.exit_code,
+        tsv_escape(&result.cmd)
+    )
+}
+
+/// Escape the tabs, newlines and backslashes of a column of the tsv joblog, like GNU parallel, so
+/// that each row stays on its own line with its own columns
+fn tsv_escape(column: &str) -> String {
+    let mut escaped = String::with_capacity(column.len());
+    for c in column.chars() {
+        match c {
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\\' => escaped.push_str("\\\\"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The sequence number and the exit code of a row of the tsv joblog
+fn parse_tsv_record(line: &str) -> Result<Recorded, String> {
+    let columns: Vec<&str> = line.split('\t').collect();
+    if columns.len() < 9 {
+        return Err("missing columns".to_string());
+    }
+    let seq: usize = columns[0]
+        .trim()
+        .parse()
+        .map_err(|_| "invalid Seq".to_string())?;
+    let exit_code = columns[6]
+        .trim()
+        .parse()
+        .map_err(|_| "invalid Exitval".to_string())?;
+    match seq.checked_sub(1) {
+        Some(seq) => Ok(Recorded { seq, exit_code }),
+        None => Err("invalid Seq".to_string()),
+    }
+}
+
+pub fn create_logger(opts: &Opts) {
+    let level = match (opts.quiet, opts.verbose) {
+        (true, _) => LevelFilter::Error,
+        (_, 0) => LevelFilter::Warn,
+        (_, 1) => LevelFilter::Info,
+        (_, 2) => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let config = Config::default();
+    let mut loggers: Vec<Box<dyn SharedLogger>> =
+        vec![TermLogger::new(level, config, TerminalMode::Stderr).unwrap()];
+    if let Some(file) = &opts.debug_log {
+        if opts.log.as_ref() == Some(file) {
+            eprintln!("The joblog and the debug log can't be written to the same file");
+            std::process::exit(1);
+        }
+        loggers.push(WriteLogger::new(
+            LevelFilter::Info,
+            config,
+            File::create(file).unwrap(),
+        ));
+    }
+    CombinedLogger::init(loggers).unwrap();
+}
+
+/// Read the jobs recorded in the joblog of a previous run
+///
+/// When a job was run several times, only its last record is kept. The lines that can't be
+/// parsed, such as one left half-written by an interrupted run, are ignored.
+pub fn read_joblog(path: &Path, format: JoblogFormat) -> BTreeMap<usize, i32> {
+    let file = match File::open(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return BTreeMap::new(),
+        Err(err) => {
+            error!(
+                "Could not open joblog '{}' for reading: {}",
+                path.to_string_lossy(),
+                err
+            );
+            std::process::exit(1);
+        }
+        Ok(file) => file,
+    };
+    let mut recorded = BTreeMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.expect("Could not read the joblog");
+        let record = match format {
+            JoblogFormat::Json => {
+                serde_json::from_str::<Recorded>(&line).map_err(|err| err.to_string())
+            }
+            JoblogFormat::Tsv if line == TSV_HEADER => continue,
+            JoblogFormat::Tsv => parse_tsv_record(&line),
+        };
+        match record {
+            Ok(record) => {
+                recorded.insert(record.seq, record.exit_code);
+            }
+            Err(err) => warn!("Ignoring invalid joblog line '{}': {}", line, err),
+        }
+    }
+    recorded
+}
+
+/// Open the joblog, starting it with the header row of the tsv format when it is empty
+pub fn create_joblog(path: &Path, append: bool, format: JoblogFormat) -> BufWriter<File> {
+    let opened = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(false)
+        .open(path)
+        .and_then(|file| {
+            lock_joblog(&file, path)?;
+            // Only emptied once the other runs are done with it
+            if !append {
+                file.set_len(0)?;
+            }
+            let empty = file.metadata()?.len() == 0;
+            let mut joblog = BufWriter::new(file);
+            if format == JoblogFormat::Tsv && empty {
+                writeln!(joblog, "{}", TSV_HEADER)?;
+            }
+            Ok(joblog)
+        });
+    match opened {
+        Err(err) => {
+            error!(
+                "Could not open joblog '{}' for writing: {}",
+                path.to_string_lossy(),
+                err
+            );
+            std::process::exit(1);
+        }
+        Ok(joblog) => joblog,
+    }
+}
+
+/// Lock the joblog until the run is done, so that the records of two runs sharing it don't mix.
+/// Waits for the other run if it has the lock
+fn lock_joblog(file: &File, path: &Path) -> io::Result<()> {
+    let flock = |operation| {
+        // Safe, as the descriptor stays open for the duration of the call
+        match unsafe { libc::flock(file.as_raw_fd(), operation) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    };
+    match flock(libc::LOCK_EX | libc::LOCK_NB) {
+        Err(ref err) if err.raw_os_error() == Some(libc::EWOULDBLOCK) => {
+            warn!(
+                "The joblog '{}' is used by another run, waiting for it to be done",
+                path.to_string_lossy()
+            );
+            flock(libc::LOCK_EX)
+        }
+        locked => locked,
+    }
+}
+
+/// Write the output of a job to a file, through gzip if `compress`
+fn write_data<R: Read, W: Write>(mut data: R, mut file: W, compress: bool) -> io::Result<()> {
+    if compress {
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        io::copy(&mut data, &mut encoder)?;
+        encoder.finish().map(drop)
+    } else {
+        io::copy(&mut data, &mut file).map(drop)
+    }
+}
+
+/// The longest name of a directory of --results-format by-arg, well below the limit of the
+/// file systems
+const MAX_ARG_DIR: usize = 200;
+
+/// The name of the directory of a job with --results-format by-arg: its single argument, or else
+/// its arguments with the characters that could be troublesome in a path replaced by `_`, then
+/// the hash of the arguments so that different ones don't share a directory
+fn arg_dir(args: &[String]) -> String {
+    let safe = |c: char| matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_');
+    match args {
+        // Neither empty, nor the current or the parent directory
+        [arg]
+            if arg.len() <= MAX_ARG_DIR && arg.chars().all(safe) && arg.contains(|c| c != '.') =>
+        {
+            arg.clone()
+        }
+        _ => {
+            let name: String = args
+                .join("_")
+                .chars()
+                .map(|c| if safe(c) { c } else { '_' })
+                .take(MAX_ARG_DIR - 17)
+                .collect();
+            format!("{}_{:016x}", name, hash_args(args))
+        }
+    }
+}
+
+/// The 64-bit FNV-1a hash of the arguments, which unlike the hashers of std stays the same from
+/// one version to the next
+fn hash_args(args: &[String]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for arg in args {
+        let len = (arg.len() as u64).to_le_bytes();
+        for &byte in len.iter().chain(arg.as_bytes()) {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+    hash
+}
+
+/// Save the output of a job with its joblog record as metadata, in its own directory unless
+/// `format` is flat
+///
+/// The output files are `stdout.gz` and `stderr.gz` (or `seq.out.gz` and `seq.err.gz`) when they
+/// are compressed.
+pub fn write_results(
+    dir: &Path,
+    result: &JobResult,
+    compress: bool,
+    format: ResultsFormat,
+) -> io::Result<()> {
+    // The files of stdout, stderr and the metadata
+    let in_dir = ["stdout", "stderr", "meta"].map(String::from);
+    let (dir, names) = match format {
+        ResultsFormat::Tree => (dir.join(result.seq.to_string()), in_dir),
+        ResultsFormat::ByArg => (dir.join(arg_dir(&result.args)), in_dir),
+        ResultsFormat::Flat => (
+            dir.to_path_buf(),
+            ["out", "err", "meta"].map(|ext| format!("{}.{}", result.seq, ext)),
+        ),
+    };
+    fs::create_dir_all(&dir)?;
+    if format != ResultsFormat::Flat {
+        fs::write(dir.join("seq"), format!("{}\n", result.seq))?;
+    }
+    let suffix = if compress { ".gz" } else { "" };
+    for (name, data) in names
+        .iter()
+        .zip([result.output.read_stdout()?, result.output.read_stderr()?])
+    {
+        let file = File::create(dir.join(format!("{}{}", name, suffix)))?;
+        write_data(data, file, compress)?;
+    }
+    let meta = serde_json::to_string(&LogRecord::from(result))?;
+    fs::write(dir.join(&names[2]), meta + "\n")
+}
+
+pub fn write_joblog(joblog: &mut BufWriter<File>, result: &JobResult, format: JoblogFormat) {
+    let written = match format {
+        JoblogFormat::Json => serde_json::to_writer(&mut *joblog, &LogRecord::from(result))
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(joblog)),
+        JoblogFormat::Tsv => writeln!(joblog, "{}", tsv_record(result)),
+    }
+    // A whole record at a time, which can't be mixed with the records of another run
+    .and_then(|_| joblog.flush());
+    if let Err(err) = written {
+        error!("Could not write to the joblog: {}", err);
+    }
+}
+
+/// The header row of the summary written with --summary-csv
+const SUMMARY_HEADER: &str = "seq,arg,exit_code,duration_seconds";
+
+/// A row of the summary, the part of a job result kept until the run is done
+#[derive(Debug)]
+pub struct SummaryRow {
+    seq: usize,
+    arg: String,
+    exit_code: i32,
+    duration: f64,
+}
+
+impl<'a> From<&'a JobResult> for SummaryRow {
+    fn from(result: &'a JobResult) -> Self {
+        SummaryRow {
+            seq: result.seq,
+            arg: result.args.join(" "),
+            exit_code: result.exit_code,
+            duration: result.duration.num_milliseconds() as f64 / 1000.,
+        }
+    }
+}
+
+/// Quote a field of a csv row when it has a comma, a quote or a line break in it
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// The csv of the summary, with a header row
+fn summary(rows: &[SummaryRow]) -> String {
+    let mut rows: Vec<&SummaryRow> = rows.iter().collect();
+    rows.sort_by_key(|row| row.seq);
+    let mut csv = format!("{}\n", SUMMARY_HEADER);
+    for row in rows {
+        csv += &format!(
+            "{},{},{},{:.3}\n",
+            row.seq + 1,
+            csv_field(&row.arg),
+            row.exit_code,
+            row.duration
+        );
+    }
+    csv
+}
+
+/// Write the summary of the run, a row per job in the order of the input
+pub fn write_summary(path: &Path, rows: &[SummaryRow]) {
+    if let Err(err) = fs::write(path, summary(rows)) {
+        error!(
+            "Could not write the summary to '{}': {}",
+            path.to_string_lossy(),
+            err
+        );
+    }
+}
+
+/// Save the stdout of a job to a new file in `tmpdir`, which is kept, and return its path. Its
+/// stderr is written to ours
+pub fn save_output(tmpdir: &Path, output: &Output, compress: bool) -> io::Result<PathBuf> {
+    let mut file = TempFile::new(tmpdir)?;
+    write_data(output.read_stdout()?, file.file(), compress)?;
+    io::copy(&mut output.read_stderr()?, &mut io::stderr().lock())?;
+    Ok(file.keep())
+}
+
+/// A result held back by --keep-order, whose output may wait in the spill file
+#[derive(Debug)]
+pub struct Held {
+    result: JobResult,
+    /// Where the stdout then the stderr of the job start in the spill file, and their lengths
+    spilled: Option<(u64, u64, u64)>,
+}
+
+impl From<JobResult> for Held {
+    fn from(result: JobResult) -> Self {
+        Held {
+            result,
+            spilled: None,
+        }
+    }
+}
+
+/// The outputs of the results held back beyond --keep-order-buffer, appended one after the other
+/// to a single temporary file in `tmpdir`, so that they take neither memory nor a file each
+#[derive(Debug)]
+pub struct Spill {
+    tmpdir: PathBuf,
+    /// Created with the first output spilled
+    file: Option<TempFile>,
+    /// Where the next output is appended
+    len: u64,
+    /// How many of the results held back have their output in the file
+    held: usize,
+}
+
+impl Spill {
+    pub fn new(tmpdir: PathBuf) -> Self {
+        Spill {
+            tmpdir,
+            file: None,
+            len: 0,
+            held: 0,
+        }
+    }
+
+    /// Move the output of the result, spooled or in memory, to the spill file, or keep it where
+    /// it is if that fails
+    pub fn hold(&mut self, mut result: JobResult) -> Held {
+        let offset = self.len;
+        match self.append(&result.output) {
+            Ok((stdout_len, stderr_len)) => {
+                self.len += stdout_len + stderr_len;
+                self.held += 1;
+                // Removes the spool files
+                result.output = Output::default();
+                Held {
+                    result,
+                    spilled: Some((offset, stdout_len, stderr_len)),
+                }
+            }
+            Err(err) => {
+                warn!(
+                    "Could not spill the output of job {} to '{}', keeping it as is: {}",
+                    result.seq,
+                    self.tmpdir.to_string_lossy(),
+                    err
+                );
+                Held::from(result)
+            }
+        }
+    }
+
+    /// Append the stdout then the stderr, and return their lengths
+    fn append(&mut self, output: &Output) -> io::Result<(u64, u64)> {
+        if self.file.is_none() {
+            self.file = Some(TempFile::new(&self.tmpdir)?);
+        }
+        let file = self
+            .file
+            .as_mut()
+            .expect("The spill file was created")
+            .file();
+        // Anything after the end was left by an append that failed
+        file.seek(SeekFrom::Start(self.len))?;
+        let mut writer = BufWriter::new(file);
+        let stdout_len = io::copy(&mut output.read_stdout()?, &mut writer)?;
+        let stderr_len = io::copy(&mut output.read_stderr()?, &mut writer)?;
+        writer.flush()?;
+        Ok((stdout_len, stderr_len))
+    }
+
+    /// The result held back, with its output read back if it was spilled
+    pub fn release(&mut self, held: Held) -> JobResult {
+        let Held {
+            mut result,
+            spilled,
+        } = held;
+        let (offset, stdout_len, stderr_len, file) = match (spilled, &mut self.file) {
+            (Some((offset, stdout_len, stderr_len)), Some(file)) => {
+                (offset, stdout_len, stderr_len, file.file())
+            }
+            _ => return result,
+        };
+        let mut stdout = vec![0; stdout_len as usize];
+        let mut stderr = vec![0; stderr_len as usize];
+        let read = file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut stdout))
+            .and_then(|_| file.read_exact(&mut stderr));
+        match read {
+            Ok(_) => {
+                result.output.stdout = stdout;
+                result.output.stderr = stderr;
+            }
+            Err(err) => error!(
+                "Could not read back the output of job {} from the spill file: {}",
+                result.seq, err
+            ),
+        }
+        self.held -= 1;
+        // The file starts over once nothing in it is held back anymore
+        if self.held == 0 {
+            self.len = 0;
+            let _ = file.set_len(0);
+        }
+        result
+    }
+}
+
+/// Print a command or a path to stdout, ended with a NUL byte if `null` or else a newline
+pub fn print_record(record: &str, null: bool) {
+    let end = if null { '\0' } else { '\n' };
+    let written = write!(io::stdout().lock(), "{}{}", record, end);
+    if let Err(err) = written {
+        error!("Could not write to stdout: {}", err);
+    }
+}
+
+/// Write the output of a job that was held until it was done, streamed from its spool if it was
+/// spooled
+pub fn write_output(output: &Output) {
+    let written = output
+        .read_stdout()
+        .and_then(|mut stdout| io::copy(&mut stdout, &mut io::stdout().lock()))
+        .and_then(|_| output.read_stderr())
+        .and_then(|mut stderr| io::copy(&mut stderr, &mut io::stderr().lock()));
+    if let Err(err) = written {
+        error!("Could not write the output of a job: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Local};
+    use parallelion::Spool;
+    use std::collections::HashSet;
+
+    #[test]
+    fn reads_back_the_last_record_of_each_job() {
+        for &(format, truncated) in &[
+            (JoblogFormat::Json, "{\"seq\":"),
+            (JoblogFormat::Tsv, "3\t:"),
+        ] {
+            let path = std::env::temp_dir().join(format!(
+                "parallelion-joblog-{:?}-{}",
+                format,
+                std::process::id()
+            ));
+            let mut joblog = create_joblog(&path, false, format);
+            for &(seq, exit_code) in &[(0, 1), (1, 0), (0, 0)] {
+                let result = JobResult {
+                    seq,
+                    args: vec!["a".to_string()],
+                    exit_code,
+                    start: Local::now(),
+                    duration: Duration::milliseconds(1500),
+                    cmd: "echo a".to_string(),
+                    attempts: 1,
+                    host: None,
+                    slot: 1,
+                    output: Output::default(),
+                };
+                write_joblog(&mut joblog, &result, format);
+            }
+            writeln!(joblog, "{}", truncated).unwrap();
+            joblog.flush().unwrap();
+            drop(joblog);
+
+            let recorded = read_joblog(&path, format);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(
+                recorded.into_iter().collect::<Vec<_>>(),
+                vec![(0, 0), (1, 0)]
+            );
+        }
+    }
+
+    #[test]
+    fn names_the_directories_after_the_arguments() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(arg_dir(&args(&["file-1.txt"])), "file-1.txt");
+        assert!(arg_dir(&args(&["dir/file.txt"])).starts_with("dir_file.txt_"));
+        assert!(arg_dir(&args(&["a b", "c"])).starts_with("a_b_c_"));
+        assert!(arg_dir(&args(&[".."])).starts_with(".._"));
+        assert!(arg_dir(&args(&[""])).starts_with('_'));
+        assert_eq!(arg_dir(&args(&[&"x".repeat(300)])).len(), MAX_ARG_DIR);
+    }
+
+    #[test]
+    fn gives_different_arguments_different_directories() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let long = "x".repeat(300);
+        let dirs: HashSet<String> = [
+            args(&["a/b"]),
+            args(&["a b"]),
+            args(&["a_b"]),
+            args(&["a", "b"]),
+            args(&["a_", "b"]),
+            args(&[&long]),
+            args(&[&(long.clone() + "y")]),
+        ]
+        .iter()
+        .map(|args| arg_dir(args))
+        .collect();
+        assert_eq!(dirs.len(), 7);
+        assert_eq!(arg_dir(&args(&["a/b"])), arg_dir(&args(&["a/b"])));
+    }
+
+    #[test]
+    fn spills_the_outputs_to_a_single_file_and_reads_them_back() {
+        let result = |seq, stdout: &[u8], stderr: &[u8]| JobResult {
+            seq,
+            args: vec!["a".to_string()],
+            exit_code: 0,
+            start: Local::now(),
+            duration: Duration::zero(),
+            cmd: "echo a".to_string(),
+            attempts: 1,
+            host: None,
+            slot: 1,
+            output: Output {
+                stdout: stdout.to_vec(),
+                stderr: stderr.to_vec(),
+                ..Output::default()
+            },
+        };
+        let tmpdir = std::env::temp_dir();
+        let mut spill = Spill::new(tmpdir.clone());
+        let file = TempFile::new(&tmpdir).unwrap();
+        let spooled = Spool::new(file, true, |out| out.write_all(b"spooled\n")).unwrap();
+        let spool = spooled.path().to_path_buf();
+        let mut first = result(0, b"", b"err\n");
+        first.output.stdout_spool = Some(spooled);
+        let first = spill.hold(first);
+        let second = spill.hold(result(1, b"out\n", b""));
+        assert!(!spool.exists());
+        let path = spill.file.as_ref().unwrap().path().to_path_buf();
+        assert_eq!(fs::read(&path).unwrap(), b"spooled\nerr\nout\n");
+        let second = spill.release(second);
+        assert_eq!(second.output.stdout, b"out\n");
+        let first = spill.release(first);
+        assert_eq!(first.output.stdout, b"spooled\n");
+        assert_eq!(first.output.stderr, b"err\n");
+        // The file starts over once nothing is held back
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn escapes_the_command_of_the_tsv_joblog() {
+        assert_eq!(tsv_escape("printf 'a\tb\n' \\"), "printf 'a\\tb\\n' \\\\");
+        assert_eq!(tsv_escape("echo a"), "echo a");
+    }
+
+    #[test]
+    fn quotes_the_summary_fields() {
+        let rows = vec![
+            SummaryRow {
+                seq: 1,
+                arg: "say \"hi\", twice".to_string(),
+                exit_code: 0,
+                duration: 0.25,
+            },
+            SummaryRow {
+                seq: 0,
+                arg: "plain".to_string(),
+                exit_code: 2,
+                duration: 1.,
+            },
+        ];
+        assert_eq!(
+            summary(&rows),
+            "seq,arg,exit_code,duration_seconds\n1,plain,2,1.000\n2,\"say \"\"hi\"\", twice\",0,0.250\n"
+        );
+    }
+}
@@ -1,259 +1,594 @@
-use chrono::{DateTime, Duration, Local};
-use crossbeam_channel::{Receiver, Sender};
-use indicatif::{ProgressBar, ProgressStyle};
-use ion_shell::Shell;
+mod cli;
+mod collect;
+mod environment;
+mod logging;
+mod plan;
+mod progress;
+
+use crate::{
+    cli::{split_sources, unescape, Interrupt, Jobs, Opts},
+    collect::{Collector, Recorder, Reorder},
+    environment::{forwarded_vars, record_env},
+    logging::{create_joblog, create_logger, read_joblog, Spill},
+    progress::Progress,
+};
 use log::{debug, error, info, trace, warn};
-use simplelog::*;
+use parallelion::{
+    add_jobs, default_max_chars, parse_sshlogins, queue_capacity, read_header, run_jobs,
+    start_workers, Backend, Format, HillClimb, Inputs, OutputMode, Permit, Pipe, Runner, Semaphore,
+    Source,
+};
+use rand::{rngs::StdRng, SeedableRng};
+use regex::Regex;
+use signal_hook::iterator::Signals;
 use std::{
-    fs::File,
-    io::{self, BufRead, BufReader},
-    path::PathBuf,
-    sync::Arc,
+    collections::HashSet,
+    env, fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     thread,
+    time::{Duration as StdDuration, Instant},
 };
 use structopt::StructOpt;
 
-#[derive(Debug, StructOpt)]
-#[structopt(name = "parallelion", about = "An example of StructOpt usage.")]
-struct Opts {
-    /// Show progress
-    ///
-    /// Displays % of jobs completed, ETA, number of jobs running, number of jobs started
-    #[structopt(short, long)]
-    progress: bool,
-
-    /// Silence all output
-    #[structopt(short = "q", long = "quiet")]
-    quiet: bool,
-    /// Increase verbosity (0 = normal, 1 = info, 2 = debug)
-    #[structopt(short, long, parse(from_occurrences))]
-    verbose: usize,
-    /// Log the executed jobs to the following file
-    ///
-    /// The format used is a json with the following fields: sequence number (seq), start time
-    /// (start), duration in floating-point seconds (duration), command run (cmd), exit status (exit_code)
-    #[structopt(short, long, parse(from_os_str))]
-    log: Option<PathBuf>,
-
-    /// Print the jobs to stdout, but don't execute them
-    #[structopt(long = "dry-run")]
-    dry_run: bool,
-
-    /// Halt on error in a command
-    #[structopt(long = "halt-on-error")]
-    halt: bool,
-
-    /// Ask the user before running each command
-    #[structopt(short, long)]
-    interactive: bool,
+/// Print the running jobs to stderr on SIGUSR1, like GNU parallel. When `resizable`, run one
+/// more job at once on SIGUSR2 and one less on SIGUSR1
+fn handle_user_signals(runner: Arc<Runner>, resizable: bool) {
+    let signals = match Signals::new(&[signal_hook::SIGUSR1, signal_hook::SIGUSR2]) {
+        Err(err) => {
+            warn!("Could not install the signal handlers: {}", err);
+            return;
+        }
+        Ok(signals) => signals,
+    };
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == signal_hook::SIGUSR1 {
+                eprint!("{}", running_jobs(&runner));
+            }
+            if !resizable {
+                continue;
+            }
+            let limit = runner.pool.limit();
+            let limit = if signal == signal_hook::SIGUSR2 {
+                runner.pool.resize(limit + 1)
+            } else {
+                runner.pool.resize(limit.saturating_sub(1))
+            };
+            info!("Running up to {} jobs at once", limit);
+        }
+    });
+}
 
-    /// Start n jobs in parallel. Defaults to the number of cores available. 0 indicates to run one
-    /// thread per job
-    #[structopt(short, long)]
-    jobs: Option<usize>,
+/// How often --jobs auto measures the throughput and adjusts the number of jobs
+const AUTOSCALE_INTERVAL: StdDuration = StdDuration::from_secs(2);
+/// The most jobs --jobs auto runs at once, per core
+const AUTO_JOBS_PER_CORE: usize = 4;
 
-    /// Each line of the argfile will be treated as a replacement on the input
-    #[structopt(short, long = "arg-file", parse(from_os_str))]
-    argfile: Option<PathBuf>,
+/// Adjust the number of jobs running at once towards the highest throughput, for --jobs auto
+fn autoscale(runner: Arc<Runner>, max: usize) {
+    thread::spawn(move || {
+        let mut climb = HillClimb::new(runner.pool.limit(), max);
+        let mut completed = runner.counters.completed.load(Ordering::SeqCst);
+        let mut since = Instant::now();
+        loop {
+            thread::sleep(AUTOSCALE_INTERVAL);
+            let now = runner.counters.completed.load(Ordering::SeqCst);
+            let elapsed = since.elapsed();
+            let limit = runner.pool.resize(climb.observe(now - completed, elapsed));
+            debug!(
+                "{} jobs done in {:.1}s, running up to {} jobs at once",
+                now - completed,
+                elapsed.as_secs_f64(),
+                limit
+            );
+            completed = now;
+            since = Instant::now();
+        }
+    });
+}
 
-    // Positionals
-    /// The command to run. '{}' tokens will be replaced with the list of arguments
-    command: String,
-    /// The list of arguments
-    arguments: Vec<String>,
+/// The commands running, a line each with their slot and for how long they have been running
+fn running_jobs(runner: &Runner) -> String {
+    let running = runner.running.list();
+    let mut report = format!("{} job(s) running:\n", running.len());
+    for (slot, cmd, elapsed) in running {
+        report.push_str(&format!(
+            "{}\t{:.1}s\t{}\n",
+            slot,
+            elapsed.as_secs_f64(),
+            cmd
+        ));
+    }
+    report
 }
 
-#[derive(Debug, Clone, PartialEq)]
-struct JobResult {
-    seq: usize,
-    exit_code: i32,
-    start: DateTime<Local>,
-    duration: Duration,
-    cmd: String,
+/// The backends the jobs can be run with, and whether they are built in or external programs:
+/// Ion and no shell with --shell none are built in, while sh and the ssh of --sshlogin must be in
+/// the PATH
+const BACKENDS: &[(&str, &str)] = &[
+    ("ion", "built in"),
+    ("none", "built in"),
+    ("sh", "external"),
+    ("ssh", "external"),
+];
+
+/// Print the version with what is needed to tell how the jobs would be run on this machine
+fn print_version() {
+    let backends: Vec<String> = BACKENDS
+        .iter()
+        .map(|(name, kind)| format!("{} ({})", name, kind))
+        .collect();
+    println!("parallelion {}", env!("CARGO_PKG_VERSION"));
+    println!("backends: {}", backends.join(", "));
+    println!("cores: {}", num_cpus::get());
 }
 
-fn add_jobs(
-    command: Arc<String>,
-    arguments: Vec<String>,
-    argfile: Option<PathBuf>,
-    ask: bool,
-    tx: Sender<String>,
-) {
-    let mut i = 0;
-    let mut always = false;
-    let mut start = |arg: String| {
-        let command = command.replace("{}", &arg);
-        if ask && !always {
-            loop {
-                eprint!("Do '{}'? [Y/n/a]: ", command);
-                let mut input = String::new();
-                if io::stdin()
-                    .read_line(&mut input)
-                    .expect("Failed to read line")
-                    == 0
-                {
-                    error!("Could not read from stdin in interactive mode");
-                    std::process::exit(1);
-                }
-                match input.trim() {
-                    "y" | "Y" | "yes" | "Yes" | "" => break,
-                    "n" | "N" | "no" | "No" => return,
-                    "a" | "A" | "all" | "All" | "always" | "Always" => {
-                        always = true;
-                        break;
-                    }
-                    _ => eprintln!("Invalid choice"),
-                }
-            }
+/// Run the command alone once a slot of the semaphore is free, or wait for the semaphore
+fn run_semaphore(opts: Opts) -> ! {
+    let cores = num_cpus::get();
+    let size = opts.jobs.map_or(1, |jobs| jobs.resolve(cores));
+    if size == 0 {
+        error!("--semaphore requires a positive number of jobs");
+        std::process::exit(1);
+    }
+    let tmpdir = opts.tmpdir.clone().unwrap_or_else(env::temp_dir);
+    let id = opts.semaphore_id.as_deref().unwrap_or("default");
+    let semaphore = match Semaphore::new(&tmpdir, id, size) {
+        Ok(semaphore) => semaphore,
+        Err(err) => {
+            error!("Could not create the semaphore '{}': {}", id, err);
+            std::process::exit(1);
         }
-        debug!("Starting {}: '{}'", i, command.replace("{}", &arg));
-        tx.send(arg.to_string()).unwrap();
-        i += 1;
     };
-    if arguments.is_empty() {
-        if let Some(argfile) = argfile {
-            let file = match File::open(&argfile) {
-                Err(err) => {
-                    error!(
-                        "Could not open arg file '{}' for reading: {}",
-                        argfile.to_string_lossy(),
-                        err
-                    );
-                    std::process::exit(1);
-                }
-                Ok(file) => file,
-            };
-            for arg in BufReader::new(file).lines() {
-                let arg = arg.expect("Could not read the file");
-                start(arg)
-            }
-        } else {
-            for arg in BufReader::new(io::stdin()).lines() {
-                let arg = arg.expect("Could not stdin");
-                start(arg)
-            }
+    if opts.semaphore_wait {
+        if let Err(err) = semaphore.wait() {
+            error!("Could not wait for the semaphore '{}': {}", id, err);
+            std::process::exit(1);
         }
-    } else {
-        arguments.into_iter().for_each(start);
+        std::process::exit(0);
     }
-}
-
-fn create_logger(opts: &Opts) {
-    let level = match (opts.quiet, opts.verbose) {
-        (true, _) => LevelFilter::Error,
-        (_, 0) => LevelFilter::Warn,
-        (_, 1) => LevelFilter::Info,
-        (_, 2) => LevelFilter::Debug,
-        _ => LevelFilter::Trace,
+    // The words after the command belong to it, as there are no arguments to run it with
+    let mut command = opts.command.unwrap_or_default();
+    for word in &opts.arguments {
+        command.push(' ');
+        command.push_str(word);
+    }
+    if command.trim().is_empty() {
+        error!("The command is empty");
+        std::process::exit(1);
+    }
+    // The run left in the background is told apart by the permit in its environment, and runs in
+    // the foreground
+    let inherited = Permit::inherited();
+    let background = opts.background && inherited.is_none();
+    let permit = match inherited.map_or_else(|| semaphore.acquire(), Ok) {
+        Ok(permit) => permit,
+        Err(err) => {
+            error!("Could not acquire the semaphore '{}': {}", id, err);
+            std::process::exit(1);
+        }
     };
-    let config = Config::default();
-    let mut loggers: Vec<Box<dyn SharedLogger>> =
-        vec![TermLogger::new(level, config, TerminalMode::Stderr).unwrap()];
-    if let Some(file) = &opts.log {
-        loggers.push(WriteLogger::new(
-            LevelFilter::Info,
-            config,
-            File::create(file).unwrap(),
-        ));
+    if background {
+        // The same run, left running with the permit once we exit
+        let spawned = env::current_exe().and_then(|exe| {
+            let mut command = Command::new(exe);
+            command.args(env::args_os().skip(1)).stdin(Stdio::null());
+            permit.pass_on(&mut command);
+            command.spawn()
+        });
+        match spawned {
+            Ok(_) => std::process::exit(0),
+            Err(err) => {
+                error!("Could not start the command in the background: {}", err);
+                std::process::exit(1);
+            }
+        }
     }
-    CombinedLogger::init(loggers).unwrap();
+    let config = parallelion::Config {
+        command,
+        jobs: 1,
+        backend: if opts.exec { Backend::Exec } else { opts.shell },
+        output: OutputMode::Ungroup,
+        ..parallelion::Config::default()
+    };
+    let mut exit_code = 0;
+    run_jobs(&config, vec![Vec::new()], |result| {
+        exit_code = result.exit_code
+    });
+    drop(permit);
+    std::process::exit(exit_code);
 }
 
-// TODO: Add a feature to use Ion as an external command
-fn run(check_only: bool, cmd: &str) -> i32 {
-    let mut shell = Shell::default();
-    shell.opts_mut().no_exec = check_only;
-    match shell.execute_command(cmd.as_bytes()) {
-        Err(err) => {
-            error!("could not execute command '{}': {}", cmd, err);
-            1
-        }
-        Ok(_) => shell.previous_status().as_os_code(),
+/// Where the arguments of the jobs are read from, and how they are split into jobs
+fn create_inputs(opts: &mut Opts, sources: Vec<Source>, max_args: usize, copies: usize) -> Inputs {
+    Inputs {
+        arguments: std::mem::take(&mut opts.arguments),
+        argfiles: std::mem::take(&mut opts.argfiles),
+        sources,
+        link: opts.link,
+        format: Format {
+            null: opts.null,
+            skip_comments: opts.skip_comments,
+        },
+        no_run_if_empty: opts.no_run_if_empty,
+        trim: opts.trim,
+        colsep: opts.colsep.clone(),
+        header_lines: 0,
+        max_args,
+        continued_lines: opts.max_lines.is_some(),
+        max_chars: opts.max_chars.unwrap_or_else(default_max_chars),
+        shuffle: if opts.shuf {
+            Some(match opts.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            })
+        } else {
+            None
+        },
+        pipe: if opts.pipe {
+            Some(Pipe {
+                block: opts.block,
+                recend: unescape(&opts.recend),
+                recstart: unescape(&opts.recstart),
+            })
+        } else {
+            None
+        },
+        copies,
+        stdin_input: opts.stdin_input,
     }
 }
 
-fn start_workers(
-    n: usize,
-    check_only: bool,
-    task: &Arc<String>,
-    jobs: Receiver<String>,
-    results: Sender<JobResult>,
-) {
-    debug!("Starting {} worker threads", n);
-    for seq in 0..n {
-        let jobs = jobs.clone();
-        let results = results.clone();
-        let task = task.clone();
-        thread::spawn(move || {
-            while let Ok(job) = jobs.recv() {
-                let start = Local::now();
-                let cmd = task.replace("{}", &job);
-                let exit_code = run(check_only, &cmd);
-                let duration = start.signed_duration_since(Local::now());
-                results
-                    .send(JobResult {
-                        seq,
-                        start,
-                        duration,
-                        cmd,
-                        exit_code,
-                    })
-                    .unwrap();
-            }
-        });
+/// How the jobs are run, from the options that are about the commands rather than the results
+fn create_config(
+    opts: &mut Opts,
+    columns: Vec<String>,
+    jobs: usize,
+    copies: usize,
+    cores: usize,
+) -> parallelion::Config {
+    parallelion::Config {
+        command: opts.command.take().unwrap_or_default(),
+        tag: match opts.tagstring.take() {
+            Some(tagstring) => Some(tagstring),
+            None if opts.tag => Some(opts.replace.clone()),
+            None => None,
+        },
+        number_lines: opts.number_lines,
+        replace: opts.replace.clone(),
+        columns,
+        column_ranges: opts.colsep.is_some(),
+        plus: opts.plus,
+        quote: opts.quote,
+        rules: std::mem::take(&mut opts.rules),
+        jobs,
+        sshlogins: std::mem::take(&mut opts.sshlogins),
+        transfer: opts.transfer,
+        returns: std::mem::take(&mut opts.returns),
+        cleanup: opts.cleanup,
+        onall: opts.onall,
+        copies,
+        backend: if opts.exec {
+            Backend::Exec
+        } else {
+            opts.shell.clone()
+        },
+        output: if opts.ungroup {
+            OutputMode::Ungroup
+        } else if opts.line_buffer {
+            OutputMode::LineBuffer
+        } else {
+            OutputMode::Group
+        },
+        retries: opts.retries,
+        retry_delay: opts.retry_delay.unwrap_or_default(),
+        retry_backoff: opts.retry_backoff.unwrap_or(1.),
+        retry_max_delay: opts.retry_max_delay,
+        timeout: opts.timeout,
+        delay: opts.delay,
+        jitter: opts.jitter.unwrap_or_default(),
+        seed: opts.seed,
+        load: opts.load.map(|load| load.resolve(cores)),
+        memfree: opts.memfree,
+        nice: opts.nice,
+        workdir: opts.workdir.take(),
+        env: if opts.env.is_empty() {
+            None
+        } else {
+            Some(forwarded_vars(std::mem::take(&mut opts.env)))
+        },
+        dry_run: opts.dry_run,
+        interactive: opts.interactive,
+        show_commands: opts.show_commands,
+        round_robin: opts.round_robin,
+        keep_stdin: opts.keep_stdin,
+        tmpdir: opts.tmpdir.take().unwrap_or_else(env::temp_dir),
+        compress: opts.compress,
     }
 }
 
 fn main() {
-    let opts = Opts::from_args();
+    let (args, mut sources) = split_sources(env::args_os().collect());
+    let mut opts = Opts::from_iter(args);
+    if !sources.is_empty() || (opts.link && opts.argfiles.len() > 1) {
+        let argfiles = std::mem::take(&mut opts.argfiles);
+        sources.splice(0..0, argfiles.into_iter().map(Source::File));
+    }
+    if let (false, Some(command)) = (sources.is_empty(), &mut opts.command) {
+        for word in std::mem::take(&mut opts.arguments) {
+            command.push(' ');
+            command.push_str(&word);
+        }
+    }
+    opts.dry_run |= opts.plan;
+    // `--log +path` appends to the joblog, like GNU parallel
+    let appended = opts
+        .log
+        .as_deref()
+        .and_then(Path::to_str)
+        .and_then(|path| path.strip_prefix('+'))
+        .map(PathBuf::from);
+    let append_log = appended.is_some();
+    if append_log {
+        opts.log = appended;
+    }
     trace!("{:#?}", opts);
     create_logger(&opts);
+    if opts.version {
+        print_version();
+        return;
+    }
+    if opts.record_env {
+        record_env();
+        return;
+    }
+    if opts.semaphore {
+        run_semaphore(opts);
+    }
+    if opts.shellquote {
+        if opts.command.is_some() {
+            error!("--shellquote quotes the arguments, it doesn't take a command");
+            std::process::exit(1);
+        }
+        // The commands of a dry run with the whole argument quoted are the quoted arguments
+        opts.command = Some(opts.replace.clone());
+        opts.quote = true;
+        opts.dry_run = true;
+        opts.keep_order = true;
+        opts.exec = false;
+        if opts.shell == Backend::Exec {
+            opts.shell = Backend::Ion;
+        }
+    }
+    if opts
+        .command
+        .as_deref()
+        .map_or(true, |command| command.trim().is_empty())
+    {
+        error!("The command is empty");
+        std::process::exit(1);
+    }
 
-    let (tx, rx) = crossbeam_channel::unbounded();
-    let (rtx, rrx) = crossbeam_channel::unbounded();
+    if opts.header.is_some() && (!sources.is_empty() || opts.pipe) {
+        error!("--header can't be used with ':::', '::::' or --pipe");
+        std::process::exit(1);
+    }
+    if opts.header.is_some() && opts.colsep.is_none() {
+        opts.colsep = Some(Regex::new("\t").unwrap());
+    }
 
-    let command = Arc::new(opts.command);
-    start_workers(
-        opts.jobs
-            .unwrap_or_else(num_cpus::get)
-            .min(opts.arguments.len()),
-        opts.dry_run,
-        &command,
-        rx,
-        rtx,
-    );
+    // Nothing can be asked without a terminal, so nothing would run
+    if opts.interactive && !opts.dry_run {
+        if let Err(err) = fs::File::open("/dev/tty") {
+            error!(
+                "--interactive requires a terminal to read the answers: {}",
+                err
+            );
+            std::process::exit(1);
+        }
+    }
+
+    let resume = opts.resume || opts.resume_failed;
+    if resume && opts.log.is_none() {
+        error!("--resume and --resume-failed require a joblog given with --log");
+        std::process::exit(1);
+    }
+    let joblog_format = opts.joblog_format;
+    let skip: HashSet<usize> = match &opts.log {
+        Some(path) if opts.resume_failed => read_joblog(path, joblog_format)
+            .into_iter()
+            .filter(|&(_, exit_code)| exit_code == 0)
+            .map(|(seq, _)| seq)
+            .collect(),
+        Some(path) if opts.resume => read_joblog(path, joblog_format).into_keys().collect(),
+        _ => HashSet::new(),
+    };
+    // A dry run doesn't run anything to record
+    let joblog = opts
+        .log
+        .as_deref()
+        .filter(|_| !opts.dry_run)
+        .map(|path| create_joblog(path, resume || append_log, joblog_format));
 
-    let pb = if opts.arguments.is_empty() {
-        ProgressBar::new_spinner()
+    let max_args = match (opts.max_args.or(opts.max_lines), opts.max_chars) {
+        (Some(max_args), _) => max_args.max(1),
+        // Only limited by the length of the command
+        (None, Some(_)) => usize::MAX,
+        (None, None) => 1,
+    };
+    // Only known for inline arguments
+    let lengths = sources.iter().map(|source| match source {
+        Source::Inline(values) => Some(values.len()),
+        Source::File(_) => None,
+    });
+    let total_inputs = if sources.is_empty() {
+        Some(opts.arguments.len()).filter(|&len| len > 0)
+    } else if opts.link {
+        lengths
+            .collect::<Option<Vec<_>>>()
+            .and_then(|lengths| lengths.into_iter().min())
     } else {
-        ProgressBar::new(opts.arguments.len() as u64)
+        lengths.product()
     };
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{prefix:.green}: [{elapsed_precise}] [{bar:40}] {pos:>7}/{len:7} ({eta})")
-            .progress_chars("█▇▆▅▄▃▂▁  "),
-    );
-    pb.set_prefix("Progress");
-    add_jobs(command, opts.arguments, opts.argfile, opts.interactive, tx);
+    // The commands can't be counted in advance when they are filled up to a length, when lines
+    // can go on with the next ones, or before the header is read
+    let total_jobs = total_inputs
+        .filter(|_| opts.max_chars.is_none() && opts.max_lines.is_none() && opts.header.is_none())
+        .map(|inputs| inputs.div_ceil(max_args));
 
-    let mut exit = 0;
-    while let Ok(result) = rrx.recv() {
-        pb.inc(1);
-        if !opts.dry_run {
-            info!("'{}' took {}s", result.cmd, result.duration);
-            if result.exit_code != 0 {
-                warn!(
-                    "'{}' exited with status code {}",
-                    result.cmd, result.exit_code
-                );
-                if opts.halt {
-                    std::process::exit(1);
-                } else {
-                    exit = 1;
-                }
+    let cores = num_cpus::get();
+    let jobs = opts.jobs.map_or(cores, |jobs| jobs.resolve(cores));
+    for path in &opts.sshloginfiles {
+        let logins = fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| parse_sshlogins(&text));
+        match logins {
+            Ok(logins) => opts.sshlogins.extend(logins),
+            Err(err) => {
+                error!("could not read sshloginfile '{}': {}", path.display(), err);
+                std::process::exit(1);
             }
         }
     }
-    pb.finish_with_message("done");
-    std::process::exit(exit);
+    if opts.sshlogins.iter().any(|login| login.slots(jobs) == 0) {
+        error!("--sshlogin requires a positive number of jobs");
+        std::process::exit(1);
+    }
+    let copies = match (opts.onall, opts.sshlogins.len(), opts.copies) {
+        (false, _, _) => 1,
+        (true, 0, Some(copies)) if copies > 0 => copies,
+        (true, 0, _) => {
+            error!("--onall requires --sshlogin or a positive number of --copies");
+            std::process::exit(1);
+        }
+        (true, _, Some(_)) => {
+            error!("--copies can't be used with --sshlogin, --onall runs a copy on each host");
+            std::process::exit(1);
+        }
+        (true, logins, None) => logins,
+    };
+    let total_jobs = total_jobs.map(|total_jobs| total_jobs * copies);
+    // Don't start more workers than there are jobs, unless the jobs are streamed or spread over
+    // several hosts
+    let jobs = match total_jobs {
+        Some(total_jobs) if jobs != 0 && opts.sshlogins.is_empty() => jobs.min(total_jobs),
+        _ => jobs,
+    };
+    let workers = if opts.sshlogins.is_empty() {
+        jobs
+    } else {
+        opts.sshlogins.iter().map(|login| login.slots(jobs)).sum()
+    };
+    let (tx, rx) = crossbeam_channel::bounded(queue_capacity(workers));
+    let (rtx, rrx) = crossbeam_channel::unbounded();
+    if opts.round_robin && (!opts.pipe || workers == 0) {
+        error!("--round-robin requires --pipe and a positive number of jobs");
+        std::process::exit(1);
+    }
+
+    // The jobs reading the terminal stay in its process group, which its Ctrl-C reaches
+    if opts.keep_stdin && opts.on_interrupt != Interrupt::Kill && io::stdin().is_terminal() {
+        error!("--on-interrupt drain and wait can't be used with --keep-stdin on a terminal");
+        std::process::exit(1);
+    }
+
+    if opts.files && (opts.ungroup || opts.line_buffer) {
+        error!("--files can't be used with --ungroup or --line-buffer");
+        std::process::exit(1);
+    }
+    if opts.results.is_some() && (opts.ungroup || opts.line_buffer) {
+        error!("--results can't be used with --ungroup or --line-buffer");
+        std::process::exit(1);
+    }
+
+    if let Some(tmpdir) = opts.tmpdir.as_ref().filter(|tmpdir| !tmpdir.is_dir()) {
+        error!("The tmpdir '{}' isn't a directory", tmpdir.display());
+        std::process::exit(1);
+    }
+
+    if opts.replace.is_empty() {
+        error!("The replacement string can't be empty");
+        std::process::exit(1);
+    }
+    let mut inputs = create_inputs(&mut opts, sources, max_args, copies);
+    let columns = match &opts.header {
+        Some(header) => read_header(&mut inputs, header),
+        None => Vec::new(),
+    };
+    let config = create_config(&mut opts, columns, jobs, copies, cores);
+    let template = Arc::new(config.command_template());
+    if let (Some(_), Err(err)) = (&opts.header, template.check_columns()) {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+    if !opts.no_warn && config.backend != Backend::Exec && template.single_quoted_replace() {
+        if config.quote {
+            warn!(
+                "{} is between single quotes in the command, which --quote already adds: remove \
+                 them, or use --no-warn to hide this warning",
+                config.replace
+            );
+        } else {
+            warn!(
+                "{} is between single quotes in the command, which an argument with a ' in it \
+                 ends: use --quote instead, or --no-warn to hide this warning",
+                config.replace
+            );
+        }
+    }
+    let runner = Arc::new(Runner::new(&config));
+    let stop = Arc::new(AtomicBool::new(false));
+    // Only used to count the jobs waiting for a worker
+    let queue = rx.clone();
+    start_workers(config.workers(), &runner, &template, &stop, rx, rtx);
+    // The slots of the other hosts are fixed, and so are the commands fed with --round-robin
+    let resizable = config.workers() > 0 && config.sshlogins.is_empty() && !config.round_robin;
+    handle_user_signals(runner.clone(), resizable);
+    match opts.jobs {
+        Some(Jobs::Auto) if resizable => autoscale(runner.clone(), AUTO_JOBS_PER_CORE * cores),
+        Some(Jobs::Auto) => {
+            warn!("--jobs auto can't change the number of jobs with --sshlogin or --round-robin")
+        }
+        _ => {}
+    }
+    let progress = Progress::new(&opts, total_jobs, workers, runner.clone(), queue);
+
+    let order = if opts.keep_order {
+        let mut order = Reorder::new();
+        for &seq in &skip {
+            order.skip(seq);
+        }
+        Some(order)
+    } else {
+        None
+    };
+    let producer_stop = stop.clone();
+    let producer_template = template.clone();
+    let (etx, erx) = crossbeam_channel::bounded(1);
+    thread::spawn(move || {
+        if let Err(err) = add_jobs(producer_template, inputs, skip, producer_stop, tx) {
+            let _ = etx.send(err);
+        }
+    });
+
+    let collector = Collector {
+        results: rrx,
+        input_errors: erx,
+        recorder: Recorder::new(&mut opts, joblog, &config, &template),
+        progress,
+        runner,
+        stop,
+        order,
+        keep_order_buffer: opts.keep_order_buffer,
+        spill: Spill::new(config.tmpdir.clone()),
+        on_interrupt: opts.on_interrupt,
+        total_timeout: opts.total_timeout,
+    };
+    std::process::exit(collector.run());
 }
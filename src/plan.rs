@@ -0,0 +1,143 @@
+//! The plan of a dry run: the slot and the host of each job, and the values of its tokens
+
+use parallelion::JobResult;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// How a job would be run, as decided by the workers of a dry run
+#[derive(Debug)]
+pub struct PlanRow {
+    seq: usize,
+    slot: usize,
+    host: Option<String>,
+    tokens: Vec<(String, String)>,
+    cmd: String,
+}
+
+/// A line of the plan with --progress-format json
+#[derive(Debug, Serialize)]
+struct PlanRecord<'a> {
+    seq: usize,
+    slot: usize,
+    /// `:` for this machine, like in the joblog
+    host: &'a str,
+    tokens: BTreeMap<&'a str, &'a str>,
+    cmd: &'a str,
+}
+
+impl PlanRow {
+    /// The row of a job, with the values of the tokens of its command
+    pub fn new(result: &JobResult, tokens: Vec<(String, String)>) -> Self {
+        PlanRow {
+            seq: result.seq,
+            slot: result.slot,
+            host: result.host.clone(),
+            tokens,
+            cmd: result.cmd.clone(),
+        }
+    }
+
+    fn host(&self) -> &str {
+        self.host.as_deref().unwrap_or(":")
+    }
+}
+
+/// The columns of the plan: seq (starting at 1, like `{#}`), slot, the host with `remote`, a
+/// column per token of the command and the command
+fn plan_table(rows: &[PlanRow], remote: bool) -> Vec<Vec<String>> {
+    let mut header = vec!["seq".to_string(), "slot".to_string()];
+    if remote {
+        header.push("host".to_string());
+    }
+    // The jobs share the command, hence its tokens
+    if let Some(row) = rows.first() {
+        header.extend(row.tokens.iter().map(|(token, _)| token.clone()));
+    }
+    header.push("command".to_string());
+    let mut table = vec![header];
+    for row in rows {
+        let mut columns = vec![(row.seq + 1).to_string(), row.slot.to_string()];
+        if remote {
+            columns.push(row.host().to_string());
+        }
+        columns.extend(row.tokens.iter().map(|(_, value)| value.clone()));
+        columns.push(row.cmd.clone());
+        table.push(columns);
+    }
+    table
+}
+
+/// The plan as aligned columns, with a header row
+fn aligned(table: &[Vec<String>]) -> String {
+    let mut widths = Vec::new();
+    for columns in table {
+        widths.resize(widths.len().max(columns.len()), 0);
+        for (width, column) in widths.iter_mut().zip(columns) {
+            *width = (*width).max(column.chars().count());
+        }
+    }
+    let mut out = String::new();
+    for columns in table {
+        let mut line = String::new();
+        for (i, column) in columns.iter().enumerate() {
+            if i + 1 == columns.len() {
+                line.push_str(column);
+            } else {
+                line.push_str(&format!("{:width$}  ", column, width = widths[i]));
+            }
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Print the plan of the run to stdout in the order of the input, as aligned columns or as a
+/// json object per job
+pub fn print_plan(rows: &mut [PlanRow], remote: bool, json: bool) {
+    rows.sort_by_key(|row| row.seq);
+    if !json {
+        print!("{}", aligned(&plan_table(rows, remote)));
+        return;
+    }
+    for row in rows.iter() {
+        let record = PlanRecord {
+            seq: row.seq + 1,
+            slot: row.slot,
+            host: row.host(),
+            tokens: row
+                .tokens
+                .iter()
+                .map(|(token, value)| (token.as_str(), value.as_str()))
+                .collect(),
+            cmd: &row.cmd,
+        };
+        println!(
+            "{}",
+            serde_json::to_string(&record).expect("The plan can be serialized")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligns_the_columns() {
+        let row = |seq: usize, value: &str| PlanRow {
+            seq,
+            slot: 1,
+            host: None,
+            tokens: vec![("{1}".to_string(), value.to_string())],
+            cmd: format!("echo {}", value),
+        };
+        let rows = vec![row(0, "a"), row(1, "longer")];
+        assert_eq!(
+            aligned(&plan_table(&rows, false)),
+            "seq  slot  {1}     command\n\
+             1    1     a       echo a\n\
+             2    1     longer  echo longer\n"
+        );
+    }
+}
@@ -0,0 +1,395 @@
+//! The progress bar and the estimation of the time left
+
+use crate::cli::{BarStyle, Opts, ProgressFormat};
+use chrono::Duration;
+use crossbeam_channel::Receiver;
+use indicatif::{HumanDuration, ProgressBar, ProgressStyle};
+use log::{error, warn};
+use parallelion::{Counters, Job, Runner};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    env,
+    fs::File,
+    io::{self, Write},
+    os::unix::io::FromRawFd,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration as StdDuration, Instant},
+};
+
+/// The progress bar characters, from full to empty, for the terminals that can display them
+const UNICODE_BAR_CHARS: &str = "█▉▊▋▌▍▎▏ ";
+/// The progress bar characters for the other terminals
+const ASCII_BAR_CHARS: &str = "=> ";
+
+/// The progress bar of the run, hidden unless --progress or --eta is given, or when the progress
+/// is written as lines of text
+///
+/// It is a spinner when the number of jobs isn't known.
+pub fn create_bar(opts: &Opts, total_jobs: Option<usize>) -> ProgressBar {
+    let elsewhere = opts.progress_format == ProgressFormat::Json
+        || opts.progress_fd.is_some()
+        || opts.progress_file.is_some();
+    let pb = if !(opts.progress || opts.eta) || elsewhere {
+        ProgressBar::hidden()
+    } else if let Some(total_jobs) = total_jobs {
+        ProgressBar::new(total_jobs as u64)
+    } else {
+        ProgressBar::new_spinner()
+    };
+    let style = opts.bar_style.unwrap_or_else(|| {
+        if unicode_terminal() {
+            BarStyle::Unicode
+        } else {
+            BarStyle::Ascii
+        }
+    });
+    pb.set_style(bar_style(
+        style,
+        total_jobs.is_some(),
+        opts.eta,
+        opts.bar_chars.as_deref(),
+    ));
+    pb.set_prefix("Progress");
+    pb
+}
+
+/// The template of a bar style, or of its spinner when the number of jobs isn't `known`
+fn bar_template(style: BarStyle, known: bool, eta: bool) -> String {
+    let prefix = match style {
+        BarStyle::Unicode => "{prefix:.green}: [{elapsed_precise}] ",
+        BarStyle::Ascii => "{prefix}: [{elapsed_precise}] ",
+        BarStyle::Minimal | BarStyle::PercentOnly => "",
+    };
+    let eta = if eta { " ({eta})" } else { "" };
+    let rest = match (style, known) {
+        (BarStyle::Unicode, false) | (BarStyle::Ascii, false) => {
+            "{spinner} {pos} done, {msg}".to_string()
+        }
+        (BarStyle::Unicode, true) | (BarStyle::Ascii, true) => {
+            format!("[{{bar:40}}] {{pos:>7}}/{{len:7}}{} {{msg}}", eta)
+        }
+        (BarStyle::Minimal, false) => "{pos} done, {msg}".to_string(),
+        (BarStyle::Minimal, true) => format!("{{pos}}/{{len}}{} {{msg}}", eta),
+        (BarStyle::PercentOnly, false) => "{pos} done".to_string(),
+        (BarStyle::PercentOnly, true) => "{percent}%".to_string(),
+    };
+    format!("{}{}", prefix, rest)
+}
+
+/// The progress style of a preset, whose bar is drawn with `chars` if given
+fn bar_style(style: BarStyle, known: bool, eta: bool, chars: Option<&str>) -> ProgressStyle {
+    let template = bar_template(style, known, eta);
+    if !known {
+        return ProgressStyle::default_spinner().template(&template);
+    }
+    let default_chars = match style {
+        BarStyle::Unicode => UNICODE_BAR_CHARS,
+        BarStyle::Ascii | BarStyle::Minimal | BarStyle::PercentOnly => ASCII_BAR_CHARS,
+    };
+    ProgressStyle::default_bar()
+        .template(&template)
+        .progress_chars(chars.unwrap_or(default_chars))
+}
+
+/// Where the progress is written as lines of text, if it isn't drawn as a bar on the terminal
+pub fn progress_writer(opts: &Opts) -> Option<Box<dyn Write>> {
+    let opened = if let Some(fd) = opts.progress_fd {
+        // Safe, as F_GETFD only checks that the descriptor is open
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            // Safe, as the descriptor is open and only used for the progress
+            Ok(unsafe { File::from_raw_fd(fd) })
+        }
+    } else if let Some(path) = &opts.progress_file {
+        File::create(path)
+    } else if opts.progress_format == ProgressFormat::Json {
+        return Some(Box::new(io::stderr()));
+    } else {
+        return None;
+    };
+    match opened {
+        Ok(file) => Some(Box::new(file)),
+        Err(err) => {
+            error!("Could not open the progress output: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// A line of text showing the progress, for the outputs that aren't a terminal
+pub fn text_progress(
+    counters: &Counters,
+    total_jobs: Option<usize>,
+    left: Option<StdDuration>,
+) -> String {
+    let completed = counters.completed.load(Ordering::SeqCst);
+    let done = match total_jobs {
+        Some(total_jobs) => format!("{}/{} done", completed, total_jobs),
+        None => format!("{} done", completed),
+    };
+    let eta = left.map_or(String::new(), |left| {
+        format!(", ETA {}", HumanDuration(left))
+    });
+    format!("{}, {}{}", done, counters.message(), eta)
+}
+
+/// Whether the locale is UTF-8 and the terminal isn't a dumb one
+fn unicode_terminal() -> bool {
+    let utf8 = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|locale| !locale.is_empty())
+        .is_some_and(|locale| {
+            let locale = locale.to_lowercase();
+            locale.contains("utf-8") || locale.contains("utf8")
+        });
+    utf8 && env::var("TERM").as_deref() != Ok("dumb")
+}
+
+/// How many of the last jobs are used to estimate the remaining time
+const ETA_SAMPLES: usize = 20;
+/// How many jobs must be done before the remaining time can be estimated
+const ETA_MIN_SAMPLES: usize = 3;
+
+/// Estimates the time left from the average duration of the last jobs
+#[derive(Debug, Default)]
+pub struct Eta {
+    durations: VecDeque<StdDuration>,
+}
+
+impl Eta {
+    pub fn push(&mut self, duration: Duration) {
+        if self.durations.len() == ETA_SAMPLES {
+            self.durations.pop_front();
+        }
+        self.durations
+            .push_back(duration.to_std().unwrap_or_default());
+    }
+
+    /// The time needed to run `remaining` jobs with `workers` jobs in parallel, once enough jobs
+    /// are done
+    pub fn estimate(&self, remaining: usize, workers: usize) -> Option<StdDuration> {
+        if self.durations.len() < ETA_MIN_SAMPLES {
+            return None;
+        }
+        let average = self.durations.iter().sum::<StdDuration>() / self.durations.len() as u32;
+        Some(average.mul_f64(remaining as f64 / workers.max(1) as f64))
+    }
+}
+
+/// How often the progress bar is drawn, unless --progress-interval is given
+pub const PROGRESS_INTERVAL: StdDuration = StdDuration::from_millis(200);
+/// How often a line is written when the progress isn't drawn on the terminal, unless
+/// --progress-interval is given
+pub const PROGRESS_LINE_INTERVAL: StdDuration = StdDuration::from_millis(500);
+
+/// The progress of the run, drawn as a bar on the terminal or written as lines of text
+pub struct Progress {
+    pb: ProgressBar,
+    /// Where the lines are written, when the progress isn't drawn on the terminal
+    out: Option<Box<dyn Write>>,
+    eta: Eta,
+    /// indicatif estimates the time left itself when the number of jobs is known
+    estimate: bool,
+    json: bool,
+    /// The number of jobs expected
+    total_jobs: Option<usize>,
+    workers: usize,
+    runner: Arc<Runner>,
+    /// The jobs waiting for a worker
+    queue: Receiver<Job>,
+    /// How many jobs are done
+    received: u64,
+    /// The progress is only shown on these ticks, not on every job done. They are our own rather
+    /// than the steady ticks of indicatif, so that the lines written elsewhere follow them too
+    pub ticks: Receiver<Instant>,
+}
+
+impl Progress {
+    /// The progress of `counted` jobs run by `workers` workers, or of the ones given with
+    /// --total-jobs
+    pub fn new(
+        opts: &Opts,
+        counted: Option<usize>,
+        workers: usize,
+        runner: Arc<Runner>,
+        queue: Receiver<Job>,
+    ) -> Self {
+        let out = progress_writer(opts);
+        // The workers are only limited by the jobs counted, the bar goes up to the ones expected
+        // with --total-jobs when they can't be counted
+        let total_jobs = counted.or(opts.total_jobs);
+        let ticks = if out.is_some() {
+            crossbeam_channel::tick(opts.progress_interval.unwrap_or(PROGRESS_LINE_INTERVAL))
+        } else if opts.progress || opts.eta {
+            crossbeam_channel::tick(opts.progress_interval.unwrap_or(PROGRESS_INTERVAL))
+        } else {
+            crossbeam_channel::never()
+        };
+        Progress {
+            pb: create_bar(opts, total_jobs),
+            out,
+            eta: Eta::default(),
+            estimate: opts.eta && total_jobs.is_none(),
+            json: opts.progress_format == ProgressFormat::Json,
+            total_jobs,
+            workers,
+            runner,
+            queue,
+            received: 0,
+            ticks,
+        }
+    }
+
+    /// Count a job done, which took `duration`
+    pub fn push(&mut self, duration: Duration) {
+        self.received += 1;
+        self.eta.push(duration);
+    }
+
+    /// The jobs left are the ones expected but not done, or else the ones waiting and running
+    fn time_left(&self) -> Option<StdDuration> {
+        let counters = &self.runner.counters;
+        let remaining = match self.total_jobs {
+            Some(total_jobs) => {
+                total_jobs.saturating_sub(counters.completed.load(Ordering::SeqCst))
+            }
+            None => self.queue.len() + counters.running.load(Ordering::SeqCst),
+        };
+        self.eta.estimate(remaining, self.workers)
+    }
+
+    fn message(&self) -> String {
+        let counters = self.runner.counters.message();
+        if !self.estimate {
+            return counters;
+        }
+        match self.time_left() {
+            Some(left) => format!("{}, ETA {}", counters, HumanDuration(left)),
+            None => format!("{}, ETA unknown", counters),
+        }
+    }
+
+    /// The line written when the progress isn't drawn on the terminal
+    fn line(&self) -> String {
+        if self.json {
+            json_progress(&self.runner.counters, self.time_left())
+        } else {
+            text_progress(&self.runner.counters, self.total_jobs, self.time_left())
+        }
+    }
+
+    /// Write a line of progress, which isn't worth stopping for if it fails
+    fn write_line(&mut self) {
+        let line = self.line();
+        if let Some(out) = &mut self.out {
+            if let Err(err) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+                warn!("Could not write the progress: {}", err);
+            }
+        }
+    }
+
+    /// Show the progress, on each tick
+    pub fn tick(&mut self) {
+        if self.out.is_some() {
+            self.write_line();
+            return;
+        }
+        self.pb.set_message(&self.message());
+        self.pb.set_position(self.received);
+        self.pb.tick();
+    }
+
+    /// Show the last progress, leaving the bar with `reason` if the run was cut short
+    pub fn finish(&mut self, reason: Option<&str>) {
+        if self.out.is_some() {
+            self.write_line();
+        }
+        self.pb.set_position(self.received);
+        match reason {
+            Some(reason) => self.pb.abandon_with_message(reason),
+            None => self.pb.finish_with_message("done"),
+        }
+    }
+}
+
+/// A line of --progress-format json
+#[derive(Debug, Serialize)]
+struct ProgressRecord {
+    started: usize,
+    running: usize,
+    completed: usize,
+    failed: usize,
+    /// Unknown until a few jobs are done
+    eta_seconds: Option<f64>,
+}
+
+/// The JSON line of --progress-format json, without its newline
+pub fn json_progress(counters: &Counters, left: Option<StdDuration>) -> String {
+    let record = ProgressRecord {
+        started: counters.started.load(Ordering::SeqCst),
+        running: counters.running.load(Ordering::SeqCst),
+        completed: counters.completed.load(Ordering::SeqCst),
+        failed: counters.failed.load(Ordering::SeqCst),
+        eta_seconds: left.map(|left| left.as_secs_f64()),
+    };
+    serde_json::to_string(&record).expect("The progress can be serialized")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_the_bar_styles() {
+        let styles = [
+            BarStyle::Ascii,
+            BarStyle::Unicode,
+            BarStyle::Minimal,
+            BarStyle::PercentOnly,
+        ];
+        for &style in &styles {
+            for &known in &[false, true] {
+                bar_style(style, known, true, None);
+            }
+        }
+        assert_eq!(
+            bar_template(BarStyle::Unicode, true, true),
+            "{prefix:.green}: [{elapsed_precise}] [{bar:40}] {pos:>7}/{len:7} ({eta}) {msg}"
+        );
+        assert_eq!(
+            bar_template(BarStyle::Ascii, true, false),
+            "{prefix}: [{elapsed_precise}] [{bar:40}] {pos:>7}/{len:7} {msg}"
+        );
+        assert_eq!(
+            bar_template(BarStyle::Ascii, false, false),
+            "{prefix}: [{elapsed_precise}] {spinner} {pos} done, {msg}"
+        );
+        assert_eq!(
+            bar_template(BarStyle::Minimal, true, false),
+            "{pos}/{len} {msg}"
+        );
+        assert_eq!(
+            bar_template(BarStyle::PercentOnly, true, true),
+            "{percent}%"
+        );
+        assert_eq!("percent-only".parse(), Ok(BarStyle::PercentOnly));
+        assert!("fancy".parse::<BarStyle>().is_err());
+    }
+
+    #[test]
+    fn estimates_from_the_last_jobs() {
+        let mut eta = Eta::default();
+        eta.push(Duration::seconds(2));
+        eta.push(Duration::seconds(4));
+        assert_eq!(eta.estimate(10, 2), None);
+        eta.push(Duration::seconds(6));
+        assert_eq!(eta.estimate(10, 2), Some(StdDuration::from_secs(20)));
+        for _ in 0..ETA_SAMPLES {
+            eta.push(Duration::seconds(1));
+        }
+        assert_eq!(eta.estimate(3, 1), Some(StdDuration::from_secs(3)));
+    }
+}
@@ -0,0 +1,159 @@
+//! Running the jobs on other machines over SSH
+
+use std::{
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+/// A machine the jobs are run on
+#[derive(Debug, Clone, PartialEq)]
+pub struct SshLogin {
+    /// The destination given to ssh, like `user@host`, or None for this machine
+    pub host: Option<String>,
+    /// How many jobs the machine runs at once, instead of the number of jobs of the others
+    pub jobs: Option<usize>,
+}
+
+impl SshLogin {
+    /// How many slots the machine has, when the others run `jobs` jobs at once
+    pub fn slots(&self, jobs: usize) -> usize {
+        self.jobs.unwrap_or(jobs)
+    }
+}
+
+impl FromStr for SshLogin {
+    type Err = String;
+
+    /// `[jobs/]host`, where `:` is this machine and any other host is given to ssh
+    fn from_str(login: &str) -> Result<Self, Self::Err> {
+        let (jobs, host) = match login.trim().split_once('/') {
+            Some((jobs, host)) => {
+                let jobs = jobs
+                    .parse()
+                    .map_err(|_| format!("'{}' isn't a number of jobs", jobs))?;
+                (Some(jobs), host)
+            }
+            None => (None, login.trim()),
+        };
+        let host = match host {
+            "" => return Err("the sshlogin is empty".to_string()),
+            ":" => None,
+            host => Some(host.to_string()),
+        };
+        Ok(SshLogin { host, jobs })
+    }
+}
+
+/// The logins of an sshloginfile, one per line. Empty lines and those starting with `#` are
+/// skipped
+pub fn parse_sshlogins(text: &str) -> Result<Vec<SshLogin>, String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::parse)
+        .collect()
+}
+
+/// The host of each slot, starting with slot 1: every login gets its slots in a row
+pub(crate) fn slot_hosts(logins: &[SshLogin], jobs: usize) -> Vec<Option<String>> {
+    logins
+        .iter()
+        .flat_map(|login| std::iter::repeat_n(login.host.clone(), login.slots(jobs)))
+        .collect()
+}
+
+/// Quote a word for a POSIX shell, so that it is a single literal word
+pub(crate) fn quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+/// The local process running `command` on `host`, with the login shell of the remote user
+pub(crate) fn ssh_command(host: &str, command: &str) -> Command {
+    let mut ssh = Command::new("ssh");
+    ssh.arg(host).arg(command);
+    ssh
+}
+
+/// The local process copying `from` to `to`, where the remote files are `host:path`
+pub(crate) fn scp_command(from: &str, to: &str) -> Command {
+    let mut scp = Command::new("scp");
+    scp.arg(from).arg(to);
+    scp
+}
+
+/// The local process removing the files from `host`
+pub(crate) fn cleanup_command<'a, I: IntoIterator<Item = &'a String>>(
+    host: &str,
+    files: I,
+) -> Command {
+    let files: Vec<String> = files.into_iter().map(|file| quote(file)).collect();
+    ssh_command(host, &format!("rm -f -- {}", files.join(" ")))
+}
+
+/// Run a command around a job and wait for it, its output is discarded
+pub(crate) fn run(mut command: Command) -> Result<(), String> {
+    match command.stdin(Stdio::null()).stdout(Stdio::null()).status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("it exited with {}", status)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gives_each_login_its_slots() {
+        let logins = vec![
+            ":".parse().unwrap(),
+            "user@host".parse::<SshLogin>().unwrap(),
+        ];
+        assert_eq!(
+            slot_hosts(&logins, 2),
+            vec![
+                None,
+                None,
+                Some("user@host".to_string()),
+                Some("user@host".to_string()),
+            ]
+        );
+        assert!("".parse::<SshLogin>().is_err());
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn reads_the_sshloginfile() {
+        let logins = parse_sshlogins("# the local jobs\n2/:\n\n  4/user@host\nother\n").unwrap();
+        assert_eq!(
+            logins,
+            vec![
+                SshLogin {
+                    host: None,
+                    jobs: Some(2),
+                },
+                SshLogin {
+                    host: Some("user@host".to_string()),
+                    jobs: Some(4),
+                },
+                SshLogin {
+                    host: Some("other".to_string()),
+                    jobs: None,
+                },
+            ]
+        );
+        assert_eq!(
+            slot_hosts(&logins, 1),
+            vec![
+                None,
+                None,
+                Some("user@host".to_string()),
+                Some("user@host".to_string()),
+                Some("user@host".to_string()),
+                Some("user@host".to_string()),
+                Some("other".to_string()),
+            ]
+        );
+        assert!(parse_sshlogins("x/host").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! A counting semaphore shared by separate runs of parallelion, made of locked files
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::{
+        io::{AsRawFd, FromRawFd},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::Duration as StdDuration,
+};
+
+/// How often to look for a free slot when they are all taken
+const SEMAPHORE_POLL_INTERVAL: StdDuration = StdDuration::from_millis(100);
+/// The variable giving the descriptor of the permit passed on to a child process
+const PERMIT_FD: &str = "PARALLELION_PERMIT_FD";
+
+/// The slots of a semaphore, a file each that is locked while a command holds it
+#[derive(Debug)]
+pub struct Semaphore {
+    dir: PathBuf,
+    size: usize,
+}
+
+/// A slot of the semaphore, released when dropped
+#[derive(Debug)]
+pub struct Permit(File);
+
+impl Semaphore {
+    /// The semaphore named `id` with `size` slots, whose files are in `dir`
+    ///
+    /// The semaphores of each user are apart, so that they can't hold each other's slots.
+    pub fn new(dir: &Path, id: &str, size: usize) -> io::Result<Self> {
+        // Safe, as getuid can't fail
+        let uid = unsafe { libc::getuid() };
+        let dir = dir
+            .join(format!("parallelion-semaphores-{}", uid))
+            .join(id.replace('/', "_"));
+        fs::create_dir_all(&dir)?;
+        Ok(Semaphore { dir, size })
+    }
+
+    /// Block until one of the slots is free, and hold it until the permit is dropped
+    pub fn acquire(&self) -> io::Result<Permit> {
+        loop {
+            for slot in 0..self.size {
+                let file = open(&self.dir.join(slot.to_string()))?;
+                if lock(&file, libc::LOCK_EX | libc::LOCK_NB)? {
+                    return Ok(Permit(file));
+                }
+            }
+            thread::sleep(SEMAPHORE_POLL_INTERVAL);
+        }
+    }
+
+    /// Block until the commands holding a slot are done, whatever the size they were run with
+    pub fn wait(&self) -> io::Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            // The lock is only taken to wait for the holder, and released right away
+            lock(&open(&entry?.path())?, libc::LOCK_EX)?;
+        }
+        Ok(())
+    }
+}
+
+impl Permit {
+    /// Let the process run by `command` inherit the permit, which it holds once we exit
+    ///
+    /// A lock taken with flock belongs to the open file, which the child shares.
+    pub fn pass_on(&self, command: &mut Command) {
+        let fd = self.0.as_raw_fd();
+        command.env(PERMIT_FD, fd.to_string());
+        // Safe, as fcntl is async-signal-safe
+        unsafe {
+            command.pre_exec(move || {
+                if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// The permit passed on by the process that started us, if any
+    pub fn inherited() -> Option<Self> {
+        let fd = env::var(PERMIT_FD).ok()?.parse().ok()?;
+        // Not for the commands we run, which mustn't hold the permit either
+        env::remove_var(PERMIT_FD);
+        // Safe, as the descriptor was left open for us by pass_on
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            Some(Permit(File::from_raw_fd(fd)))
+        }
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+/// Lock the file with flock, returns false if it would block
+fn lock(file: &File, operation: libc::c_int) -> io::Result<bool> {
+    // Safe, as the descriptor stays open for the duration of the call
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn holds_a_slot_until_the_permit_is_dropped() {
+        let dir = std::env::temp_dir();
+        let id = format!("test-{}", std::process::id());
+        let semaphore = Semaphore::new(&dir, &id, 1).unwrap();
+        let permit = semaphore.acquire().unwrap();
+        let released = thread::spawn(move || {
+            thread::sleep(StdDuration::from_millis(200));
+            drop(permit);
+        });
+        let start = Instant::now();
+        let other = Semaphore::new(&dir, &id, 1).unwrap();
+        drop(other.acquire().unwrap());
+        assert!(start.elapsed() >= StdDuration::from_millis(200));
+        released.join().unwrap();
+        semaphore.wait().unwrap();
+        fs::remove_dir_all(&semaphore.dir).unwrap();
+    }
+}
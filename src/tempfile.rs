@@ -0,0 +1,226 @@
+//! Temporary files, removed once dropped or when parallelion exits early
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{
+    collections::BTreeSet,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// The paths of the temporary files not removed yet
+static LIVE: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+/// Makes the names of the temporary files of this process unique
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// A file created in the temporary directory, removed when dropped
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    file: File,
+    /// Left in place once dropped
+    kept: bool,
+}
+
+impl TempFile {
+    /// Create a new empty file in `dir`, open for reading and writing
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        loop {
+            let name = format!(
+                "parallelion-{}-{}",
+                process::id(),
+                NEXT.fetch_add(1, Ordering::SeqCst)
+            );
+            let path = dir.join(name);
+            match OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                // Left by a previous process with the same pid
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+                Ok(file) => {
+                    LIVE.lock().unwrap().insert(path.clone());
+                    return Ok(TempFile {
+                        path,
+                        file,
+                        kept: false,
+                    });
+                }
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Leave the file in place instead of removing it, even if parallelion exits early, and
+    /// return its path
+    pub fn keep(mut self) -> PathBuf {
+        self.kept = true;
+        LIVE.lock().unwrap().remove(&self.path);
+        self.path.clone()
+    }
+
+    /// Close the file, leaving its removal to the returned path
+    fn close(mut self) -> TempPath {
+        // The path is removed by the `TempPath` instead, once it is dropped
+        self.kept = true;
+        TempPath(std::mem::take(&mut self.path))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        LIVE.lock().unwrap().remove(&self.path);
+        // The file may be gone already if the run was interrupted
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A temporary file that was closed, removed when dropped
+#[derive(Debug)]
+struct TempPath(PathBuf);
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        LIVE.lock().unwrap().remove(&self.0);
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// An output of a job spooled to a temporary file until it is written out, removed along with
+/// the last copy of the result it belongs to
+///
+/// The file is closed once written, so that the results held back don't keep files open.
+#[derive(Debug, Clone)]
+pub struct Spool {
+    path: Arc<TempPath>,
+    /// Whether the file is gzipped, with --compress
+    compressed: bool,
+}
+
+impl Spool {
+    /// Spool what `write` writes to `file`, gzipped if `compress`
+    pub fn new<F>(mut file: TempFile, compress: bool, write: F) -> io::Result<Self>
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()>,
+    {
+        let writer = BufWriter::new(file.file());
+        if compress {
+            let mut encoder = GzEncoder::new(writer, Compression::fast());
+            write(&mut encoder)?;
+            encoder.finish()?.flush()?;
+        } else {
+            let mut writer = writer;
+            write(&mut writer)?;
+            writer.flush()?;
+        }
+        Ok(Spool {
+            path: Arc::new(file.close()),
+            compressed: compress,
+        })
+    }
+
+    /// Read the output from the start
+    pub fn reader(&self) -> io::Result<Box<dyn Read>> {
+        let file = BufReader::new(File::open(self.path())?);
+        Ok(if self.compressed {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path.0
+    }
+}
+
+/// The copies of a result share the same spool
+impl PartialEq for Spool {
+    fn eq(&self, other: &Self) -> bool {
+        self.path() == other.path()
+    }
+}
+
+/// Remove every temporary file still there, before exiting without dropping them
+pub fn remove_temp_files() {
+    for path in std::mem::take(&mut *LIVE.lock().unwrap()) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn removes_the_files() {
+        let dir = std::env::temp_dir();
+        let mut dropped = TempFile::new(&dir).unwrap();
+        dropped.file().write_all(b"output").unwrap();
+        let path = dropped.path().to_path_buf();
+        assert_eq!(fs::read(&path).unwrap(), b"output");
+        drop(dropped);
+        assert!(!path.exists());
+
+        let left = TempFile::new(&dir).unwrap();
+        assert_ne!(left.path(), path);
+        let kept = TempFile::new(&dir).unwrap().keep();
+        remove_temp_files();
+        assert!(!left.path().exists());
+        assert!(kept.exists());
+        fs::remove_file(kept).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn spools_without_keeping_the_files_open() {
+        let open = || fs::read_dir("/proc/self/fd").unwrap().count();
+        let before = open();
+        let spools: Vec<Spool> = (0..100)
+            .map(|_| {
+                let file = TempFile::new(&std::env::temp_dir()).unwrap();
+                Spool::new(file, false, |out| out.write_all(b"output")).unwrap()
+            })
+            .collect();
+        // The other tests running at the same time may have a few files open
+        assert!(open() < before + 50);
+        assert!(spools.iter().all(|spool| spool.path().exists()));
+    }
+
+    #[test]
+    fn spools_until_the_last_copy_is_dropped() {
+        for &compress in &[false, true] {
+            let file = TempFile::new(&std::env::temp_dir()).unwrap();
+            let spool = Spool::new(file, compress, |out| out.write_all(b"output")).unwrap();
+            let gzipped = fs::read(spool.path()).unwrap().starts_with(&[0x1f, 0x8b]);
+            assert_eq!(gzipped, compress);
+            let copy = spool.clone();
+            drop(spool);
+            let mut data = Vec::new();
+            copy.reader().unwrap().read_to_end(&mut data).unwrap();
+            assert_eq!(data, b"output");
+            let path = copy.path().to_path_buf();
+            drop(copy);
+            assert!(!path.exists());
+        }
+    }
+}
@@ -0,0 +1,572 @@
+//! The command templates and the tokens replaced in them
+
+use crate::{remote::quote, Job};
+use ion_shell::Shell;
+use std::{path::Path, str::FromStr};
+
+/// A token defined with --rpl, replaced with the value of an Ion expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// The token, without its braces
+    token: String,
+    /// Evaluated with the whole argument in `$arg`
+    expression: String,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    /// `{token} expression`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_start();
+        let (token, expression) = match (s.find('}'), s.starts_with('{')) {
+            (Some(end), true) => (&s[1..end], s[end + 1..].trim()),
+            _ => return Err(format!("'{}' doesn't start with a {{token}}", s)),
+        };
+        if token.is_empty() || expression.is_empty() {
+            return Err(format!("'{}' needs a token and an expression", s));
+        }
+        Ok(Rule {
+            token: token.to_string(),
+            expression: expression.to_string(),
+        })
+    }
+}
+
+impl Rule {
+    /// Evaluate the expression with the embedded Ion shell
+    fn evaluate(&self, arg: &str) -> Result<String, String> {
+        let mut shell = Shell::default();
+        shell.variables_mut().set("arg", arg);
+        shell
+            .execute_command(format!("let value = {}", self.expression).as_bytes())
+            .map_err(|err| format!("could not evaluate '{}': {}", self.expression, err))?;
+        shell
+            .variables()
+            .get_str("value")
+            .map(|value| value.to_string())
+            .map_err(|err| format!("could not evaluate '{}': {}", self.expression, err))
+    }
+}
+
+/// The command to run, with the tokens to replace for each job
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    command: String,
+    /// The string replaced with the whole argument, `{}` by default
+    replace: String,
+    /// The names of the columns, from the header of the input
+    columns: Vec<String>,
+    /// Replace the tokens of GNU parallel's --plus
+    plus: bool,
+    /// Replace `{-1}`, `{2..4}`, ... as well, which a shell could otherwise expand
+    column_ranges: bool,
+    /// The tokens defined with --rpl, replaced before the others
+    rules: Vec<Rule>,
+    /// Quote the values for the shell when substituting the whole command
+    quote: bool,
+}
+
+impl Template {
+    pub fn new(command: String, replace: String) -> Self {
+        Template {
+            command,
+            replace,
+            columns: Vec::new(),
+            plus: false,
+            column_ranges: false,
+            rules: Vec::new(),
+            quote: false,
+        }
+    }
+
+    /// Quote every value replacing a token in the command run by a shell, so that the shell
+    /// sees it as a single literal word. The arguments of `{}` are quoted one by one.
+    pub fn with_quote(mut self, quote: bool) -> Self {
+        self.quote = quote;
+        self
+    }
+
+    /// Add tokens replaced with the value of Ion expressions, which take precedence over the
+    /// built-in ones
+    pub fn with_rules(mut self, rules: Vec<Rule>) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Replace the tokens of GNU parallel's --plus as well: `{+/}` is the part of the argument
+    /// before its basename and `{+.}` is the extension removed by `{.}`, both without their
+    /// separator and empty when there is none. `{..}` and `{...}` remove up to two and three
+    /// extensions. `{#string}` removes `string` from the start of the argument and `{%string}`
+    /// from its end, when it is there.
+    pub fn with_plus(mut self, plus: bool) -> Self {
+        self.plus = plus;
+        self
+    }
+
+    /// Name the columns of the arguments, so that `{name}` is replaced like `{1}`, `{2}`, ...
+    pub fn with_columns(mut self, columns: Vec<String>) -> Self {
+        self.columns = columns;
+        self
+    }
+
+    /// Replace the columns counted from the last one and the ranges of columns as well, for the
+    /// arguments split in columns. They are left to the shell otherwise, which reads `{1..3}` as
+    /// a brace expansion.
+    pub fn with_column_ranges(mut self, column_ranges: bool) -> Self {
+        self.column_ranges = column_ranges;
+        self
+    }
+
+    /// Check that every `{name}` token of the command is one of the columns, or one of the rules
+    ///
+    /// The tokens are the names made of letters, digits and underscores, without a leading
+    /// digit. Those preceded by a `$` are left to the shell.
+    pub fn check_columns(&self) -> Result<(), String> {
+        for (start, _) in self.command.match_indices('{') {
+            let token = match self.command[start + 1..].find('}') {
+                Some(end) => &self.command[start + 1..start + 1 + end],
+                None => break,
+            };
+            let is_name = token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            let in_shell = self.command[..start].ends_with('$');
+            let known = self.columns.iter().any(|column| column == token)
+                || self.rules.iter().any(|rule| rule.token == token);
+            if is_name && !in_shell && !known {
+                return Err(format!(
+                    "{{{}}} isn't a column of the header, which has {}",
+                    token,
+                    self.columns.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the token of the whole argument is between single quotes in the command
+    ///
+    /// It is still replaced there, but an argument with a `'` in it ends the quotes early, which
+    /// `with_quote` avoids. The quotes are those of a POSIX shell, where a backslash escapes the
+    /// next character outside of single quotes.
+    pub fn single_quoted_replace(&self) -> bool {
+        let (mut single, mut double, mut escaped) = (false, false, false);
+        for (i, c) in self.command.char_indices() {
+            if single && self.command[i..].starts_with(&self.replace) {
+                return true;
+            }
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if !single => escaped = true,
+                '\'' if !double => single = !single,
+                '"' if !single => double = !double,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Replace the tokens in the command with the values computed from the argument
+    ///
+    /// The tokens follow GNU parallel: `{}` is the argument, `{.}` removes its extension, `{/}` is
+    /// its basename, `{//}` is its dirname and `{/.}` is its basename without the extension. `{#}`
+    /// is the sequence number of the job, starting at 1, and is stable for a given input line.
+    /// `{%}` is the slot of the worker running the job (between 1 and the number of workers), so
+    /// it depends on which worker picked the job up and is only known once it does. `{1}`, `{2}`,
+    /// ... are the columns of the argument; referring to a column the argument doesn't have is an
+    /// error. With the column ranges, `{-1}`, `{-2}`, ... count the columns from the last one,
+    /// `{2..4}` is the columns 2 to 4 joined with spaces, `{2..}` goes on to the last column and
+    /// `{..3}` starts at the first; the ranges are clamped to the columns of the argument. With a
+    /// header, `{name}` is the column of that name. Unknown tokens are left untouched.
+    ///
+    /// With a custom replacement string, it stands for the argument instead of `{}`. Otherwise,
+    /// `{{}}` can be used for a literal `{}`.
+    pub(crate) fn substitute(&self, job: &Job, slot: Option<usize>) -> Result<String, String> {
+        self.expand(&self.command, job, slot, self.quote, |_, _| {})
+    }
+
+    /// The tokens of the command and the values replacing them for the job, in the order they
+    /// first appear in the command. The values aren't quoted.
+    pub fn tokens(&self, job: &Job, slot: Option<usize>) -> Result<Vec<(String, String)>, String> {
+        let mut tokens: Vec<(String, String)> = Vec::new();
+        self.expand(&self.command, job, slot, false, |token, value| {
+            if tokens.iter().all(|(seen, _)| seen != token) {
+                tokens.push((token.to_string(), value.to_string()));
+            }
+        })?;
+        Ok(tokens)
+    }
+
+    /// Split the command on whitespace and replace the tokens in each word, so that an argument
+    /// containing whitespace stays a single word
+    pub(crate) fn substitute_words(
+        &self,
+        job: &Job,
+        slot: Option<usize>,
+    ) -> Result<Vec<String>, String> {
+        self.command
+            .split_whitespace()
+            .map(|word| self.expand(word, job, slot, false, |_, _| {}))
+            .collect()
+    }
+
+    /// Replace the tokens in `text`, calling `replaced` with each token and its unquoted value
+    fn expand<F: FnMut(&str, &str)>(
+        &self,
+        text: &str,
+        job: &Job,
+        slot: Option<usize>,
+        quoted: bool,
+        mut replaced: F,
+    ) -> Result<String, String> {
+        let default = self.replace == "{}";
+        let whole = || {
+            if quoted {
+                job.args
+                    .iter()
+                    .map(|arg| quote(arg))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            } else {
+                job.args.join(" ")
+            }
+        };
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            if !default && rest.starts_with(&self.replace) {
+                out.push_str(&whole());
+                replaced(&self.replace, &job.args.join(" "));
+                rest = &rest[self.replace.len()..];
+                continue;
+            }
+            if default && rest.starts_with("{{}}") {
+                out.push_str("{}");
+                rest = &rest[4..];
+                continue;
+            }
+            if c == '{' {
+                if let Some(end) = rest.find('}') {
+                    let token = &rest[1..end];
+                    if default && token.is_empty() {
+                        out.push_str(&whole());
+                        replaced("{}", &job.args.join(" "));
+                        rest = &rest[end + 1..];
+                        continue;
+                    }
+                    if !token.is_empty() {
+                        if let Some(value) = self.expand_token(token, job, slot)? {
+                            replaced(&rest[..=end], &value);
+                            if quoted {
+                                out.push_str(&quote(&value));
+                            } else {
+                                out.push_str(&value);
+                            }
+                            rest = &rest[end + 1..];
+                            continue;
+                        }
+                    }
+                }
+            }
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+        Ok(out)
+    }
+
+    fn expand_token(
+        &self,
+        token: &str,
+        job: &Job,
+        slot: Option<usize>,
+    ) -> Result<Option<String>, String> {
+        let arg = job.args.join(" ");
+        let out_of_range = || {
+            format!(
+                "{{{}}} is out of range, the argument only has {} column(s)",
+                token,
+                job.args.len()
+            )
+        };
+        let column = |column: usize| match job.args.get(column - 1) {
+            Some(value) => Ok(Some(value.clone())),
+            None => Err(out_of_range()),
+        };
+        if let Some(rule) = self.rules.iter().find(|rule| rule.token == token) {
+            return rule.evaluate(&arg).map(Some);
+        }
+        if self.plus {
+            if let Some(value) = expand_plus_token(token, &arg) {
+                return Ok(Some(value));
+            }
+        }
+        Ok(match token {
+            "" => Some(arg),
+            "." => Some(remove_extension(&arg).to_string()),
+            "/" => Some(basename(&arg).to_string()),
+            "//" => Some(dirname(&arg)),
+            "/." => Some(remove_extension(basename(&arg)).to_string()),
+            "#" => Some((job.index + 1).to_string()),
+            "%" => slot.map(|slot| slot.to_string()),
+            _ => match token.parse::<isize>() {
+                Ok(index) if index > 0 => return column(index as usize),
+                // Counted from the end, where the last column is -1
+                Ok(index) if index < 0 && self.column_ranges => {
+                    return match job.args.len().checked_sub(index.unsigned_abs()) {
+                        Some(index) => column(index + 1),
+                        None => Err(out_of_range()),
+                    }
+                }
+                _ => match column_range(token).filter(|_| self.column_ranges) {
+                    Some((start, end)) => {
+                        let end = end.unwrap_or(usize::MAX).min(job.args.len());
+                        let start = start.unwrap_or(1).max(1);
+                        Some(job.args.get(start - 1..end).unwrap_or(&[]).join(" "))
+                    }
+                    None => match self.columns.iter().position(|name| name == token) {
+                        Some(index) => return column(index + 1),
+                        None => None,
+                    },
+                },
+            },
+        })
+    }
+}
+
+/// The first and last columns of a `start..end` token, where either can be left out
+fn column_range(token: &str) -> Option<(Option<usize>, Option<usize>)> {
+    let (start, end) = token.split_once("..")?;
+    let bound = |bound: &str| match bound {
+        "" => Ok(None),
+        bound => bound.parse().map(Some),
+    };
+    match (bound(start), bound(end)) {
+        (Ok(None), Ok(None)) => None,
+        (Ok(start), Ok(end)) => Some((start, end)),
+        _ => None,
+    }
+}
+
+/// The value of a token of --plus, or None if it isn't one
+fn expand_plus_token(token: &str, arg: &str) -> Option<String> {
+    match token {
+        "+/" => Some(arg.rfind('/').map_or("", |i| &arg[..i]).to_string()),
+        "+." => {
+            let stem = remove_extension(arg);
+            Some(arg[stem.len()..].trim_start_matches('.').to_string())
+        }
+        ".." => Some(remove_extension(remove_extension(arg)).to_string()),
+        "..." => Some(remove_extension(remove_extension(remove_extension(arg))).to_string()),
+        _ if token.len() > 1 && token.starts_with('#') => {
+            Some(arg.strip_prefix(&token[1..]).unwrap_or(arg).to_string())
+        }
+        _ if token.len() > 1 && token.starts_with('%') => {
+            Some(arg.strip_suffix(&token[1..]).unwrap_or(arg).to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Everything after the last slash, which is empty if the argument ends with a slash
+fn basename(arg: &str) -> &str {
+    arg.rfind('/').map_or(arg, |i| &arg[i + 1..])
+}
+
+fn dirname(arg: &str) -> String {
+    match Path::new(arg).parent() {
+        Some(parent) if parent.as_os_str().is_empty() => ".".to_string(),
+        Some(parent) => parent.to_string_lossy().into_owned(),
+        None if arg.starts_with('/') => "/".to_string(),
+        None => ".".to_string(),
+    }
+}
+
+/// Remove the extension of the last path component, if any
+fn remove_extension(arg: &str) -> &str {
+    match arg.rfind(|c| c == '.' || c == '/') {
+        Some(i) if arg[i..].starts_with('.') && i + 1 < arg.len() => &arg[..i],
+        _ => arg,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(args: &[&str]) -> Job {
+        Job {
+            index: 4,
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            input: None,
+            copy: 0,
+        }
+    }
+
+    #[test]
+    fn substitutes_the_tokens() {
+        let template = Template::new("{} {.} {/} {//} {/.} {#} {%}".to_string(), "{}".to_string());
+        assert_eq!(
+            template.substitute(&job(&["dir/file.txt"]), Some(2)),
+            Ok("dir/file.txt dir/file file.txt dir file 5 2".to_string())
+        );
+    }
+
+    #[test]
+    fn substitutes_the_columns() {
+        let template = Template::new("{2} {1} {{}}".to_string(), "{}".to_string());
+        assert_eq!(
+            template.substitute(&job(&["a", "b"]), None),
+            Ok("b a {}".to_string())
+        );
+        assert!(template.substitute(&job(&["a"]), None).is_err());
+    }
+
+    #[test]
+    fn substitutes_the_column_ranges() {
+        let command = "{2..} | {..3} | {2..3} | {-1} | {-4} | {3..9} | {5..}";
+        let template = Template::new(command.to_string(), "{}".to_string());
+        assert_eq!(
+            template.substitute(&job(&["a", "b", "c", "d"]), None),
+            Ok(command.to_string())
+        );
+        let template = template.with_column_ranges(true);
+        assert_eq!(
+            template.substitute(&job(&["a", "b", "c", "d"]), None),
+            Ok("b c d | a b c | b c | d | a | c d | ".to_string())
+        );
+        let template = Template::new("{-5}".to_string(), "{}".to_string()).with_column_ranges(true);
+        assert_eq!(
+            template.substitute(&job(&["a", "b", "c", "d"]), None),
+            Err("{-5} is out of range, the argument only has 4 column(s)".to_string())
+        );
+    }
+
+    #[test]
+    fn substitutes_the_named_columns() {
+        let columns = vec!["name".to_string(), "age".to_string()];
+        let template = Template::new("echo {name} is {age} ${HOME}".to_string(), "{}".to_string())
+            .with_columns(columns.clone());
+        assert_eq!(template.check_columns(), Ok(()));
+        assert_eq!(
+            template.substitute(&job(&["Ann", "31"]), None),
+            Ok("echo Ann is 31 ${HOME}".to_string())
+        );
+        let unknown =
+            Template::new("echo {nmae}".to_string(), "{}".to_string()).with_columns(columns);
+        assert_eq!(
+            unknown.check_columns(),
+            Err("{nmae} isn't a column of the header, which has name, age".to_string())
+        );
+    }
+
+    #[test]
+    fn substitutes_the_plus_tokens() {
+        let template = Template::new("{+/} {+.} {..} {...} {/..}".to_string(), "{}".to_string())
+            .with_plus(true);
+        assert_eq!(
+            template.substitute(&job(&["dir/a.b.tar.gz"]), None),
+            Ok("dir gz dir/a.b dir/a {/..}".to_string())
+        );
+        assert_eq!(
+            template.substitute(&job(&["file"]), None),
+            Ok("  file file {/..}".to_string())
+        );
+        let plain = Template::new("{..}".to_string(), "{}".to_string());
+        assert_eq!(
+            plain.substitute(&job(&["a.b.c"]), None),
+            Ok("{..}".to_string())
+        );
+    }
+
+    #[test]
+    fn removes_a_prefix_or_a_suffix_with_plus() {
+        let template = Template::new(
+            "{#dir/} {%.gz} {#x} {%x} {#} {%}".to_string(),
+            "{}".to_string(),
+        )
+        .with_plus(true);
+        assert_eq!(
+            template.substitute(&job(&["dir/a.gz"]), Some(3)),
+            Ok("a.gz dir/a dir/a.gz dir/a.gz 5 3".to_string())
+        );
+        assert_eq!(
+            template.substitute(&job(&["a.gz.gz"]), None),
+            Ok("a.gz.gz a.gz a.gz.gz a.gz.gz 5 {%}".to_string())
+        );
+    }
+
+    #[test]
+    fn evaluates_the_custom_rules() {
+        let rule: Rule = "{upper} $to_uppercase($arg)".parse().unwrap();
+        let template =
+            Template::new("echo {upper} {}".to_string(), "{}".to_string()).with_rules(vec![rule]);
+        assert_eq!(
+            template.substitute(&job(&["abc"]), None),
+            Ok("echo ABC abc".to_string())
+        );
+        assert!("upper $arg".parse::<Rule>().is_err());
+        assert!("{upper}".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn uses_the_custom_replacement_string() {
+        let template = Template::new("echo XX {}".to_string(), "XX".to_string());
+        assert_eq!(
+            template.substitute(&job(&["a"]), None),
+            Ok("echo a {}".to_string())
+        );
+    }
+
+    #[test]
+    fn keeps_an_argument_in_a_single_word() {
+        let template = Template::new("echo {}".to_string(), "{}".to_string());
+        assert_eq!(
+            template.substitute_words(&job(&["a b"]), None),
+            Ok(vec!["echo".to_string(), "a b".to_string()])
+        );
+    }
+
+    #[test]
+    fn quotes_the_values_for_the_shell() {
+        let template =
+            Template::new("echo {} {1} {{}}".to_string(), "{}".to_string()).with_quote(true);
+        assert_eq!(
+            template.substitute(&job(&["a b; rm -rf x", "it's"]), None),
+            Ok(r"echo 'a b; rm -rf x' 'it'\''s' 'a b; rm -rf x' {}".to_string())
+        );
+        assert_eq!(
+            template.substitute_words(&job(&["a b"]), None),
+            Ok(vec![
+                "echo".to_string(),
+                "a b".to_string(),
+                "a b".to_string(),
+                "{}".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn finds_the_argument_between_single_quotes() {
+        let quoted = |command: &str| {
+            Template::new(command.to_string(), "{}".to_string()).single_quoted_replace()
+        };
+        assert!(quoted("echo '{}'"));
+        assert!(quoted("echo 'x {}' y"));
+        assert!(!quoted("echo {} '{'"));
+        assert!(!quoted(r#"echo "{}" "it's""#));
+        assert!(!quoted(r"echo \'{}\'"));
+    }
+
+    #[test]
+    fn lists_the_values_of_the_tokens() {
+        let template = Template::new("cp {1} {2}/{/} {1}".to_string(), "{}".to_string());
+        assert_eq!(
+            template.tokens(&job(&["d/a", "b"]), None),
+            Ok(vec![
+                ("{1}".to_string(), "d/a".to_string()),
+                ("{2}".to_string(), "b".to_string()),
+                ("{/}".to_string(), "a b".to_string()),
+            ])
+        );
+    }
+}
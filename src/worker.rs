@@ -0,0 +1,1878 @@
+//! The workers, and how they run the commands
+
+use crate::{
+    remote,
+    tempfile::{Spool, TempFile},
+    template::Template,
+    Config, Job, JobResult, Output, OutputMode,
+};
+use chrono::{Duration, Local};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use ion_shell::Shell;
+use log::{debug, error, warn};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{BTreeMap, HashSet},
+    env,
+    ffi::OsString,
+    fmt, fs,
+    io::{self, BufRead, BufReader, IsTerminal, Read, Write},
+    iter,
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration as StdDuration, Instant},
+};
+
+/// What runs the commands
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    Ion,
+    /// A shell run as `shell -c command`
+    Shell(String),
+    /// No shell, the command is split in the program and its arguments before the tokens are
+    /// replaced
+    Exec,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err("the shell can't be empty".to_string()),
+            "ion" => Ok(Backend::Ion),
+            "none" => Ok(Backend::Exec),
+            _ => Ok(Backend::Shell(s.to_string())),
+        }
+    }
+}
+
+/// A command with its tokens replaced, ready to run
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    /// Interpreted by the shell
+    Shell(String),
+    /// The program and its arguments, run without a shell
+    Exec(Vec<String>),
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Line::Shell(cmd) => f.write_str(cmd),
+            Line::Exec(words) => f.write_str(&words.join(" ")),
+        }
+    }
+}
+
+/// Run the command with the embedded Ion shell, its output going straight to ours
+///
+/// The processes it starts are in our process group but not in `Children`, so the signals sent
+/// to the running jobs don't reach them.
+fn run(cmd: &str) -> i32 {
+    let mut shell = Shell::default();
+    match shell.execute_command(cmd.as_bytes()) {
+        Err(err) => {
+            error!("could not execute command '{}': {}", cmd, err);
+            1
+        }
+        Ok(_) => shell.previous_status().as_os_code(),
+    }
+}
+
+/// The exit code of a process, or 128 plus the number of the signal that killed it, like shells
+fn status_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+/// The exit code of the jobs killed because of their timeout, like GNU timeout
+const TIMEOUT_EXIT_CODE: i32 = 124;
+/// How often to check if a job with a timeout is done
+const POLL_INTERVAL: StdDuration = StdDuration::from_millis(10);
+/// How many jobs must be done before a timeout relative to their median runtime applies
+const MEDIAN_TIMEOUT_SAMPLES: usize = 3;
+/// How long a job has to exit once sent SIGTERM, before it is killed with SIGKILL
+pub const KILL_GRACE_PERIOD: StdDuration = StdDuration::from_secs(1);
+
+/// Send the signal to the process group of a job, with the processes it started, or to the job
+/// alone when it isn't in a group of its own
+fn signal_job(pid: u32, signal: libc::c_int) {
+    // Safe, as kill doesn't touch the memory of this process
+    unsafe {
+        if libc::kill(-(pid as libc::pid_t), signal) != 0 {
+            libc::kill(pid as libc::pid_t, signal);
+        }
+    }
+}
+
+/// Whether a process started by a job is still in its group, once the job itself is gone
+fn group_alive(pid: u32) -> bool {
+    // Safe, as the signal 0 only checks that the processes are there
+    unsafe { libc::kill(-(pid as libc::pid_t), 0) == 0 }
+}
+
+/// Kill a job with SIGTERM, then with SIGKILL if it is still running after the grace period
+fn terminate(child: &mut Child) -> io::Result<ExitStatus> {
+    signal_job(child.id(), libc::SIGTERM);
+    let deadline = Instant::now() + KILL_GRACE_PERIOD;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    signal_job(child.id(), libc::SIGKILL);
+    child.wait()
+}
+
+/// Give the processes left in the group of a killed job the grace period to exit, then kill them
+fn kill_group(pid: u32) {
+    let deadline = Instant::now() + KILL_GRACE_PERIOD;
+    while group_alive(pid) && Instant::now() < deadline {
+        thread::sleep(POLL_INTERVAL);
+    }
+    if group_alive(pid) {
+        signal_job(pid, libc::SIGKILL);
+    }
+}
+
+/// How long a job can run before it is killed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timeout {
+    Fixed(StdDuration),
+    /// A factor of the median runtime of the jobs done so far, like 2 for `200%`
+    Median(f64),
+}
+
+/// The timeout of the jobs, with the runtimes it depends on
+#[derive(Debug)]
+struct Deadline {
+    timeout: Timeout,
+    /// The runtimes of the commands that exited on their own, sorted
+    runtimes: Mutex<Vec<StdDuration>>,
+}
+
+impl Deadline {
+    fn new(timeout: Timeout) -> Self {
+        Deadline {
+            timeout,
+            runtimes: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// How long a command can run now, None until enough jobs are done for a relative timeout
+    fn limit(&self) -> Option<StdDuration> {
+        match self.timeout {
+            Timeout::Fixed(timeout) => Some(timeout),
+            Timeout::Median(factor) => {
+                let runtimes = self.runtimes.lock().unwrap();
+                if runtimes.len() < MEDIAN_TIMEOUT_SAMPLES {
+                    return None;
+                }
+                Some(runtimes[runtimes.len() / 2].mul_f64(factor))
+            }
+        }
+    }
+
+    /// Record the runtime of a command that wasn't killed
+    fn record(&self, runtime: StdDuration) {
+        if let Timeout::Median(_) = self.timeout {
+            let mut runtimes = self.runtimes.lock().unwrap();
+            let index = runtimes.partition_point(|&other| other < runtime);
+            runtimes.insert(index, runtime);
+        }
+    }
+}
+
+/// Prefix every line of the output with the tag
+fn tag_lines(tag: &str, output: &[u8]) -> Vec<u8> {
+    let mut tagged = Vec::with_capacity(output.len());
+    for line in output.split_inclusive(|&byte| byte == b'\n') {
+        tagged.extend_from_slice(tag.as_bytes());
+        tagged.extend_from_slice(line);
+    }
+    if !tagged.is_empty() && !tagged.ends_with(b"\n") {
+        tagged.push(b'\n');
+    }
+    tagged
+}
+
+/// Copy the output of a command with every line prefixed with the tag, without holding all of it
+fn copy_tagged<R: Read>(output: R, tag: &str, to: &mut dyn Write) -> io::Result<()> {
+    let mut output = BufReader::new(output);
+    let mut line = Vec::new();
+    while output.read_until(b'\n', &mut line)? > 0 {
+        to.write_all(&tag_lines(tag, &line))?;
+        line.clear();
+    }
+    Ok(())
+}
+
+/// Copy the output of a command to ours line by line, prefixing each line with the tag if any
+///
+/// Each line is written at once, so that the lines of concurrent jobs don't mix.
+fn forward_lines<R: Read>(output: R, tag: Option<&str>, stderr: bool) {
+    let mut output = BufReader::new(output);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match output.read_until(b'\n', &mut line) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(err) => {
+                warn!("could not read the output of the command: {}", err);
+                return;
+            }
+        }
+        let tagged;
+        let line = match tag {
+            Some(tag) => {
+                tagged = tag_lines(tag, &line);
+                &tagged
+            }
+            None => &line,
+        };
+        let written = if stderr {
+            io::stderr().lock().write_all(line)
+        } else {
+            io::stdout().lock().write_all(line)
+        };
+        if let Err(err) = written {
+            warn!("could not write the output of the command: {}", err);
+            return;
+        }
+    }
+}
+
+/// The external processes currently running, so they can be stopped along with the program
+#[derive(Debug, Clone, Default)]
+pub struct Children(Arc<Mutex<HashSet<u32>>>);
+
+impl Children {
+    fn insert(&self, pid: u32) {
+        self.0.lock().unwrap().insert(pid);
+    }
+
+    fn remove(&self, pid: u32) {
+        self.0.lock().unwrap().remove(&pid);
+    }
+
+    /// Send the signal to every running process, and to the processes they started
+    pub fn signal(&self, signal: libc::c_int) {
+        for &pid in self.0.lock().unwrap().iter() {
+            signal_job(pid, signal);
+        }
+    }
+}
+
+/// How many jobs were started, are running and are done, for the progress display
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub started: AtomicUsize,
+    pub running: AtomicUsize,
+    pub completed: AtomicUsize,
+    /// The jobs done with a non-zero exit code
+    pub failed: AtomicUsize,
+}
+
+impl Counters {
+    pub fn message(&self) -> String {
+        format!(
+            "{} running, {} started",
+            self.running.load(Ordering::SeqCst),
+            self.started.load(Ordering::SeqCst)
+        )
+    }
+}
+
+/// The commands running in each slot, with when they started
+#[derive(Debug, Default)]
+pub struct Running(Mutex<BTreeMap<usize, (String, Instant)>>);
+
+impl Running {
+    fn insert(&self, slot: usize, cmd: &str) {
+        let started = (cmd.to_string(), Instant::now());
+        self.0.lock().unwrap().insert(slot, started);
+    }
+
+    fn remove(&self, slot: usize) {
+        self.0.lock().unwrap().remove(&slot);
+    }
+
+    /// The slot of every running command, the command, and for how long it has been running
+    pub fn list(&self) -> Vec<(usize, String, StdDuration)> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&slot, (cmd, started))| (slot, cmd.clone(), started.elapsed()))
+            .collect()
+    }
+}
+
+/// How many workers take jobs, which can be changed while they run
+#[derive(Debug, Default)]
+pub struct Pool {
+    state: Mutex<PoolState>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    /// How many workers may take jobs
+    limit: usize,
+    /// How many workers were started, the ones whose slot is above the limit wait
+    spawned: usize,
+    /// Every job was taken, so the workers are done
+    done: bool,
+}
+
+impl Pool {
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Change how many workers take jobs, which is at least one, and return it. The workers
+    /// above the limit stop once they are done with their job
+    pub fn resize(&self, limit: usize) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.limit = limit.max(1);
+        self.changed.notify_all();
+        state.limit
+    }
+
+    /// Block until the slot can take a job. Returns false once every job was taken
+    fn wait(&self, slot: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.done {
+                return false;
+            }
+            if slot <= state.limit {
+                return true;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Let the waiting workers know that there are no more jobs
+    fn finish(&self) {
+        self.state.lock().unwrap().done = true;
+        self.changed.notify_all();
+    }
+}
+
+/// Seeks the number of jobs at once with the highest throughput, by moving it one job at a time
+/// and turning back whenever the throughput drops
+#[derive(Debug)]
+pub struct HillClimb {
+    limit: usize,
+    max: usize,
+    /// 1 while going up, -1 while going down
+    direction: isize,
+    /// The throughput at the previous limit
+    previous: Option<f64>,
+    /// The jobs done at the current limit and over how long, until there are enough to measure
+    done: usize,
+    elapsed: StdDuration,
+}
+
+/// The fewest jobs done at a limit for its throughput to tell it from the previous one
+const MIN_JOBS_MEASURED: usize = 3;
+
+impl HillClimb {
+    /// Start from `limit` jobs at once, never going above `max`
+    pub fn new(limit: usize, max: usize) -> Self {
+        HillClimb {
+            limit,
+            max: max.max(1),
+            direction: 1,
+            previous: None,
+            done: 0,
+            elapsed: StdDuration::from_secs(0),
+        }
+    }
+
+    /// Take the jobs done at the current limit over `elapsed`, and return the next limit to try.
+    /// The limit is held until enough jobs are done to measure its throughput
+    pub fn observe(&mut self, done: usize, elapsed: StdDuration) -> usize {
+        self.done += done;
+        self.elapsed += elapsed;
+        if self.done < MIN_JOBS_MEASURED {
+            return self.limit;
+        }
+        let throughput = self.done as f64 / self.elapsed.as_secs_f64();
+        self.done = 0;
+        self.elapsed = StdDuration::from_secs(0);
+        if self.previous.is_some_and(|previous| throughput < previous) {
+            self.direction = -self.direction;
+        }
+        self.previous = Some(throughput);
+        let next = (self.limit as isize + self.direction).clamp(1, self.max as isize) as usize;
+        // Turn back from the bounds
+        if next == self.limit {
+            self.direction = -self.direction;
+        }
+        self.limit = next;
+        next
+    }
+}
+
+/// Spaces out the start of the jobs
+#[derive(Debug)]
+struct Throttle {
+    delay: StdDuration,
+    /// The delay is drawn between `delay - jitter` and `delay + jitter`
+    jitter: StdDuration,
+    /// When the next job can start, and the generator of the jitter
+    next: Mutex<(Instant, StdRng)>,
+}
+
+impl Throttle {
+    fn new(delay: StdDuration, jitter: StdDuration, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Throttle {
+            delay,
+            jitter,
+            next: Mutex::new((Instant::now(), rng)),
+        }
+    }
+
+    /// Block until the delay since the start of the previous job has elapsed. Returns false if
+    /// the run is stopped first
+    fn wait(&self, stop: &AtomicBool) -> bool {
+        // The lock is held while sleeping so that the waiting jobs start one after the other
+        let mut next = self.next.lock().unwrap();
+        let (next, rng) = &mut *next;
+        let now = Instant::now();
+        if *next > now && sleep_unless_stopped(*next - now, stop) {
+            return false;
+        }
+        *next = Instant::now() + self.gap(rng);
+        true
+    }
+
+    /// The wait until the next job, drawn from `rng` between `delay - jitter` and `delay + jitter`
+    fn gap(&self, rng: &mut StdRng) -> StdDuration {
+        let low = self.delay.saturating_sub(self.jitter);
+        let high = self.delay + self.jitter;
+        rng.gen_range(low, high + StdDuration::from_nanos(1))
+    }
+}
+
+/// The wait before each retry of a failing job, growing by `factor` from one retry to the next
+#[derive(Debug)]
+struct Backoff {
+    delay: StdDuration,
+    factor: f64,
+    max: Option<StdDuration>,
+}
+
+impl Backoff {
+    /// The wait before the given retry, starting at 1
+    fn delay(&self, retry: usize) -> StdDuration {
+        let secs = self.delay.as_secs_f64() * self.factor.powi(retry as i32 - 1);
+        let secs = self.max.map_or(secs, |max| secs.min(max.as_secs_f64()));
+        StdDuration::try_from_secs_f64(secs).unwrap_or(StdDuration::MAX)
+    }
+}
+
+/// How often the waits between the jobs check whether the run was stopped
+const STOP_POLL_INTERVAL: StdDuration = StdDuration::from_millis(50);
+
+/// Sleep for the duration, or less once `stop` is set. Returns whether it was stopped
+fn sleep_unless_stopped(duration: StdDuration, stop: &AtomicBool) -> bool {
+    let started = Instant::now();
+    while !stop.load(Ordering::SeqCst) {
+        match duration.checked_sub(started.elapsed()) {
+            Some(left) if left > StdDuration::from_secs(0) => {
+                thread::sleep(left.min(STOP_POLL_INTERVAL))
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Where the load average of the system is read from
+trait LoadSource: fmt::Debug + Send + Sync {
+    /// The load average over the last minute, if it can be read
+    fn load(&self) -> Option<f64>;
+}
+
+/// The load average of this machine
+#[derive(Debug)]
+struct SystemLoad;
+
+impl LoadSource for SystemLoad {
+    fn load(&self) -> Option<f64> {
+        let mut load = [0.];
+        // Safe, as getloadavg writes at most one value in the array
+        match unsafe { libc::getloadavg(load.as_mut_ptr(), 1) } {
+            1 => Some(load[0]),
+            _ => None,
+        }
+    }
+}
+
+/// How often the load average is read again while it is too high
+const LOAD_POLL_INTERVAL: StdDuration = StdDuration::from_secs(1);
+
+/// Holds the start of the jobs back while the load average is above a maximum
+#[derive(Debug)]
+struct LoadGate {
+    max: f64,
+    source: Box<dyn LoadSource>,
+    poll: StdDuration,
+}
+
+impl LoadGate {
+    /// Block until the load average is at most the maximum, or can't be read. Returns false if
+    /// `stop` is set while waiting
+    fn wait(&self, stop: &AtomicBool) -> bool {
+        while let Some(load) = self.source.load().filter(|&load| load > self.max) {
+            debug!(
+                "Waiting for the load average to go from {} to {}",
+                load, self.max
+            );
+            if sleep_unless_stopped(self.poll, stop) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Where the memory available on the system is read from
+trait MemorySource: fmt::Debug + Send + Sync {
+    /// The memory available for new processes in bytes, if it can be read
+    fn available(&self) -> Option<usize>;
+}
+
+/// The memory available on this machine, from `/proc/meminfo`
+#[derive(Debug)]
+struct SystemMemory;
+
+impl MemorySource for SystemMemory {
+    fn available(&self) -> Option<usize> {
+        fs::read_to_string("/proc/meminfo")
+            .ok()
+            .and_then(|meminfo| available_memory(&meminfo))
+    }
+}
+
+/// The `MemAvailable` line of `/proc/meminfo`, in bytes
+fn available_memory(meminfo: &str) -> Option<usize> {
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kilobytes: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// How long to wait at first for memory to be freed, doubled each time it still isn't
+const MEMORY_POLL_INTERVAL: StdDuration = StdDuration::from_millis(100);
+/// The longest wait between two reads of the available memory
+const MEMORY_MAX_POLL_INTERVAL: StdDuration = StdDuration::from_secs(2);
+
+/// Holds the start of the jobs back while there isn't enough memory available
+#[derive(Debug)]
+struct MemoryGate {
+    min: usize,
+    source: Box<dyn MemorySource>,
+    poll: StdDuration,
+    max_poll: StdDuration,
+}
+
+impl MemoryGate {
+    /// Block until at least the minimum of memory is available, or it can't be read. Returns false
+    /// if `stop` is set while waiting
+    fn wait(&self, stop: &AtomicBool) -> bool {
+        let mut poll = self.poll;
+        while let Some(free) = self.source.available().filter(|&free| free < self.min) {
+            debug!(
+                "Waiting for {} bytes of memory to be available, {} are",
+                self.min, free
+            );
+            if sleep_unless_stopped(poll, stop) {
+                return false;
+            }
+            poll = (poll * 2).min(self.max_poll);
+        }
+        true
+    }
+}
+
+/// Where the answers of --interactive are read from
+struct Answers(Box<dyn BufRead + Send>);
+
+impl fmt::Debug for Answers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Answers")
+    }
+}
+
+impl Answers {
+    /// The controlling terminal, so that the answers aren't taken from the arguments on stdin
+    fn terminal() -> io::Result<Self> {
+        let tty = fs::File::open("/dev/tty")?;
+        Ok(Answers(Box::new(BufReader::new(tty))))
+    }
+}
+
+/// How the workers run the jobs
+#[derive(Debug)]
+pub struct Runner {
+    /// Only print the commands, without running them
+    dry_run: bool,
+    /// Where to read the answers when asking before running each command. The lock is held
+    /// while asking, so that the workers ask one at a time
+    answers: Option<Mutex<Answers>>,
+    /// The answer was to run every remaining command
+    always: AtomicBool,
+    /// There is no terminal left to read the answers from, so every remaining command is skipped
+    refused: AtomicBool,
+    /// Sends the jobs whose command was declined, which --keep-order doesn't wait for
+    decline: Sender<usize>,
+    pub declined: Receiver<usize>,
+    /// Print the commands to stderr when they start
+    show_commands: bool,
+    /// The prefix of the output lines of each job, with its separator
+    tag: Option<Template>,
+    /// The directory each job runs in
+    workdir: Option<Template>,
+    /// The environment of the jobs, when they don't inherit the whole one
+    env: Option<Vec<(String, OsString)>>,
+    output: OutputMode,
+    /// How many times a failing job is run again
+    retries: usize,
+    /// How long to wait before running a failing job again
+    backoff: Option<Backoff>,
+    /// How long a job can run before being killed
+    timeout: Option<Deadline>,
+    delay: Option<Throttle>,
+    load: Option<LoadGate>,
+    memfree: Option<MemoryGate>,
+    /// Feed the jobs to a single long-running command per slot
+    round_robin: bool,
+    /// Let the jobs without an input read our stdin
+    keep_stdin: bool,
+    /// Run each command in its own process group, so that it is killed with the processes it
+    /// started and the signals of the terminal don't reach it
+    process_group: bool,
+    /// Where the grouped output is spooled
+    tmpdir: PathBuf,
+    /// Spool the grouped output through gzip
+    compress: bool,
+    backend: Backend,
+    /// Added to the niceness parallelion runs with, which the jobs start with otherwise
+    nice: Option<i32>,
+    /// The host of each slot, every slot runs on this machine when empty
+    hosts: Vec<Option<String>>,
+    /// The host of each copy of the jobs with --onall, used instead of the one of the slot
+    onall: Vec<Option<String>>,
+    /// The file copied to the host of a job before it runs
+    transfer: Option<Template>,
+    /// The files copied back from the host of a job once it is done
+    returns: Vec<Template>,
+    /// Remove the copied files from the host of a job once it is done
+    cleanup: bool,
+    pub children: Children,
+    pub counters: Counters,
+    pub pool: Pool,
+    pub running: Running,
+}
+
+impl Runner {
+    pub fn new(config: &Config) -> Self {
+        // Safe, as geteuid can't fail
+        if config.nice.is_some_and(|nice| nice < 0) && unsafe { libc::geteuid() } != 0 {
+            warn!("Only root can lower the niceness, the jobs may keep the current one");
+        }
+        let answers = match config.interactive {
+            true => Answers::terminal()
+                .map_err(|err| {
+                    error!(
+                        "--interactive requires a terminal to read the answers, skipping every \
+                         command: {}",
+                        err
+                    )
+                })
+                .ok(),
+            false => None,
+        };
+        let (decline, declined) = crossbeam_channel::unbounded();
+        Runner {
+            dry_run: config.dry_run,
+            refused: AtomicBool::new(config.interactive && answers.is_none()),
+            answers: answers.map(Mutex::new),
+            always: AtomicBool::new(false),
+            decline,
+            declined,
+            show_commands: config.show_commands,
+            tag: match (&config.tag, config.number_lines) {
+                (_, true) => Some(config.template("{#}:".to_string())),
+                (Some(tag), false) => Some(config.template(format!("{}\t", tag))),
+                (None, false) => None,
+            },
+            workdir: config
+                .workdir
+                .clone()
+                .map(|workdir| config.template(workdir)),
+            env: config.env.as_ref().map(|names| {
+                iter::once("PATH")
+                    .chain(names.iter().map(String::as_str))
+                    .filter_map(|name| env::var_os(name).map(|value| (name.to_string(), value)))
+                    .collect()
+            }),
+            output: config.output,
+            retries: config.retries,
+            backoff: Some(Backoff {
+                delay: config.retry_delay,
+                factor: config.retry_backoff,
+                max: config.retry_max_delay,
+            })
+            .filter(|_| config.retry_delay > StdDuration::from_secs(0)),
+            timeout: config.timeout.map(Deadline::new),
+            delay: config
+                .delay
+                .map(|delay| Throttle::new(delay, config.jitter, config.seed)),
+            load: config.load.map(|max| LoadGate {
+                max,
+                source: Box::new(SystemLoad),
+                poll: LOAD_POLL_INTERVAL,
+            }),
+            memfree: config.memfree.map(|min| MemoryGate {
+                min,
+                source: Box::new(SystemMemory),
+                poll: MEMORY_POLL_INTERVAL,
+                max_poll: MEMORY_MAX_POLL_INTERVAL,
+            }),
+            round_robin: config.round_robin,
+            keep_stdin: config.keep_stdin,
+            // The jobs reading the terminal must stay in its foreground process group
+            process_group: !(config.keep_stdin && io::stdin().is_terminal()),
+            tmpdir: config.tmpdir.clone(),
+            compress: config.compress,
+            backend: config.backend.clone(),
+            nice: config.nice,
+            hosts: remote::slot_hosts(&config.sshlogins, config.jobs),
+            onall: config
+                .sshlogins
+                .iter()
+                .map(|login| login.host.clone())
+                .filter(|_| config.onall)
+                .collect(),
+            transfer: Some(Template::new(
+                config.replace.clone(),
+                config.replace.clone(),
+            ))
+            .filter(|_| config.transfer),
+            returns: config
+                .returns
+                .iter()
+                .map(|file| config.template(file.clone()))
+                .collect(),
+            cleanup: config.cleanup,
+            children: Children::default(),
+            counters: Counters::default(),
+            pool: Pool::default(),
+            running: Running::default(),
+        }
+    }
+
+    /// Replace the tokens of the command for the job
+    fn prepare(&self, task: &Template, job: &Job, slot: usize) -> Result<Line, String> {
+        match self.backend {
+            Backend::Exec => task.substitute_words(job, Some(slot)).map(Line::Exec),
+            _ => task.substitute(job, Some(slot)).map(Line::Shell),
+        }
+    }
+
+    /// Whether the commands left are skipped, as there is no terminal left to ask about them
+    pub fn refused(&self) -> bool {
+        self.refused.load(Ordering::SeqCst)
+    }
+
+    /// Ask whether to run the command, with --interactive. Answering `a` runs it and every next
+    /// one without asking, while a closed terminal skips it and every next one
+    fn confirm(&self, cmd: &str) -> bool {
+        if self.refused.load(Ordering::SeqCst) {
+            return false;
+        }
+        let answers = match &self.answers {
+            Some(answers) if !self.always.load(Ordering::SeqCst) => answers,
+            _ => return true,
+        };
+        let mut answers = answers.lock().unwrap();
+        // Another worker may have been answered while this one waited
+        if self.always.load(Ordering::SeqCst) {
+            return true;
+        } else if self.refused.load(Ordering::SeqCst) {
+            return false;
+        }
+        loop {
+            eprint!("Do '{}'? [Y/n/a]: ", cmd);
+            let mut input = String::new();
+            match answers.0.read_line(&mut input) {
+                Ok(0) => {
+                    error!(
+                        "The terminal was closed in interactive mode, skipping every command left"
+                    );
+                    self.refused.store(true, Ordering::SeqCst);
+                    return false;
+                }
+                Err(err) => {
+                    error!(
+                        "could not read the answer, skipping every command left: {}",
+                        err
+                    );
+                    self.refused.store(true, Ordering::SeqCst);
+                    return false;
+                }
+                Ok(_) => {}
+            }
+            match input.trim() {
+                "y" | "Y" | "yes" | "Yes" | "" => return true,
+                "n" | "N" | "no" | "No" => return false,
+                "a" | "A" | "all" | "All" | "always" | "Always" => {
+                    self.always.store(true, Ordering::SeqCst);
+                    return true;
+                }
+                _ => eprintln!("Invalid choice"),
+            }
+        }
+    }
+
+    /// The host the job runs on in the slot, or None for this machine
+    fn host(&self, job: &Job, slot: usize) -> Option<&str> {
+        match self.onall.get(job.copy) {
+            Some(host) => host.as_deref(),
+            None => self
+                .hosts
+                .get(slot.wrapping_sub(1))
+                .and_then(|host| host.as_deref()),
+        }
+    }
+
+    /// The directory the job runs in, which must exist when it is on this machine
+    fn workdir(&self, job: &Job, slot: usize) -> Result<Option<PathBuf>, String> {
+        let workdir = match &self.workdir {
+            Some(workdir) => PathBuf::from(workdir.substitute(job, Some(slot))?),
+            None => return Ok(None),
+        };
+        if self.host(job, slot).is_none() && !workdir.is_dir() {
+            return Err(format!("'{}' isn't a directory", workdir.to_string_lossy()));
+        }
+        Ok(Some(workdir))
+    }
+
+    /// The file copied to the host of the job and the ones copied back
+    fn remote_files(
+        &self,
+        job: &Job,
+        slot: usize,
+    ) -> Result<(Option<String>, Vec<String>), String> {
+        let transfer = self
+            .transfer
+            .as_ref()
+            .map(|transfer| transfer.substitute(job, Some(slot)))
+            .transpose()?;
+        let returns = self
+            .returns
+            .iter()
+            .map(|file| file.substitute(job, Some(slot)))
+            .collect::<Result<_, _>>()?;
+        Ok((transfer, returns))
+    }
+
+    /// Copy the results of the job back from its host, then remove its files if asked to.
+    /// Returns whether every result could be copied
+    fn fetch(&self, host: &str, transfer: Option<&String>, returns: &[String]) -> bool {
+        let mut fetched = true;
+        for file in returns {
+            let from = format!("{}:{}", host, file);
+            if let Err(err) = remote::run(remote::scp_command(&from, file)) {
+                error!("could not copy '{}' back from {}: {}", file, host, err);
+                fetched = false;
+            }
+        }
+        let files: Vec<&String> = transfer.into_iter().chain(returns).collect();
+        if self.cleanup && !files.is_empty() {
+            if let Err(err) = remote::run(remote::cleanup_command(host, files)) {
+                warn!(
+                    "could not remove the files of the job from {}: {}",
+                    host, err
+                );
+            }
+        }
+        fetched
+    }
+
+    fn run(
+        &self,
+        line: &Line,
+        input: Option<&[u8]>,
+        tag: Option<&str>,
+        dir: Option<&Path>,
+        host: Option<&str>,
+    ) -> (i32, Output) {
+        match line {
+            Line::Shell(cmd)
+                if self.backend == Backend::Ion
+                    && self.timeout.is_none()
+                    && self.nice.is_none()
+                    && dir.is_none()
+                    && host.is_none()
+                    && self.env.is_none()
+                    && input.is_none()
+                    && self.keep_stdin
+                    && tag.is_none()
+                    && self.output == OutputMode::Ungroup =>
+            {
+                (run(cmd), Output::default())
+            }
+            _ => self.run_process(line, input, tag, dir, host),
+        }
+    }
+
+    /// The external process running the command, in `dir` if given
+    ///
+    /// On another host, the command is run by ssh with the login shell and the environment of
+    /// the remote user.
+    fn command(&self, line: &Line, dir: Option<&Path>, host: Option<&str>) -> Command {
+        if let Some(host) = host {
+            let cmd = match line {
+                Line::Shell(cmd) => cmd.clone(),
+                Line::Exec(words) => {
+                    let words: Vec<String> = words.iter().map(|word| remote::quote(word)).collect();
+                    words.join(" ")
+                }
+            };
+            let cmd = match dir {
+                Some(dir) => format!("cd {} && {}", remote::quote(&dir.to_string_lossy()), cmd),
+                None => cmd,
+            };
+            let mut ssh = remote::ssh_command(host, &cmd);
+            if self.process_group {
+                ssh.process_group(0);
+            }
+            return ssh;
+        }
+        let mut command = match line {
+            Line::Shell(cmd) => {
+                let shell = match &self.backend {
+                    Backend::Shell(shell) => shell.as_str(),
+                    _ => "ion",
+                };
+                let mut command = Command::new(shell);
+                command.arg("-c").arg(cmd);
+                command
+            }
+            Line::Exec(words) => {
+                let (program, args) = words
+                    .split_first()
+                    .map_or(("", &[][..]), |(program, args)| (program.as_str(), args));
+                let mut command = Command::new(program);
+                command.args(args);
+                command
+            }
+        };
+        if let Some(dir) = dir {
+            command.current_dir(dir);
+        }
+        if let Some(vars) = &self.env {
+            command.env_clear().envs(vars.iter().cloned());
+        }
+        if self.process_group {
+            command.process_group(0);
+        }
+        if let Some(nice) = self.nice {
+            // Safe, as nice doesn't allocate or lock. The job keeps the current niceness when it
+            // can't be changed, which was warned about
+            unsafe {
+                command.pre_exec(move || {
+                    libc::nice(nice);
+                    Ok(())
+                });
+            }
+        }
+        command
+    }
+
+    /// Read one of the outputs of the command in another thread
+    ///
+    /// The output is sent back once the command closed it when it is grouped, and written line
+    /// by line otherwise. The grouped output is spooled to a temporary file, compressed if asked
+    /// to, and kept there until it is written out. It is only held in memory if the file can't
+    /// be created.
+    fn read_output<R: Read + Send + 'static>(
+        &self,
+        mut output: R,
+        tag: Option<&str>,
+        stderr: bool,
+    ) -> Receiver<(Vec<u8>, Option<Spool>)> {
+        let tag = tag.map(String::from);
+        let group = self.output == OutputMode::Group;
+        let tmpdir = self.tmpdir.clone();
+        let compress = self.compress;
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        thread::spawn(move || {
+            if !group {
+                forward_lines(output, tag.as_deref(), stderr);
+                let _ = tx.send((Vec::new(), None));
+                return;
+            }
+            let file = match TempFile::new(&tmpdir) {
+                Ok(file) => file,
+                Err(err) => {
+                    warn!(
+                        "could not spool the output of the command, keeping it in memory: {}",
+                        err
+                    );
+                    let mut buffer = Vec::new();
+                    if let Err(err) = output.read_to_end(&mut buffer) {
+                        warn!("could not read the output of the command: {}", err);
+                    }
+                    let _ = tx.send(match tag {
+                        Some(tag) => (tag_lines(&tag, &buffer), None),
+                        None => (buffer, None),
+                    });
+                    return;
+                }
+            };
+            let spooled = Spool::new(file, compress, |spool| match &tag {
+                Some(tag) => copy_tagged(&mut output, tag, spool),
+                None => io::copy(&mut output, spool).map(drop),
+            });
+            match spooled {
+                Ok(spool) => {
+                    let _ = tx.send((Vec::new(), Some(spool)));
+                }
+                Err(err) => {
+                    warn!("could not spool the output of the command: {}", err);
+                    let _ = tx.send((Vec::new(), None));
+                }
+            }
+        });
+        rx
+    }
+
+    /// Run the command in a process of the backend, with `input` written to its stdin and its
+    /// output lines prefixed with `tag`
+    ///
+    /// The process is killed along with the processes it started if it runs for longer than the
+    /// timeout, or once it is killed by a signal.
+    fn run_process(
+        &self,
+        cmd: &Line,
+        input: Option<&[u8]>,
+        tag: Option<&str>,
+        dir: Option<&Path>,
+        host: Option<&str>,
+    ) -> (i32, Output) {
+        let mut command = self.command(cmd, dir, host);
+        if input.is_some() {
+            command.stdin(Stdio::piped());
+        } else if !self.keep_stdin {
+            command.stdin(Stdio::null());
+        }
+        if self.output != OutputMode::Ungroup || tag.is_some() {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        let mut child = match command.spawn() {
+            Err(err) => {
+                error!("could not execute command '{}': {}", cmd, err);
+                return (1, Output::default());
+            }
+            Ok(child) => child,
+        };
+        self.children.insert(child.id());
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            let input = input.to_vec();
+            thread::spawn(move || match stdin.write_all(&input) {
+                // The command doesn't have to read all of its input
+                Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+                Err(err) => warn!("could not write the input of the command: {}", err),
+                Ok(_) => {}
+            });
+        }
+        let stdout = child
+            .stdout
+            .take()
+            .map(|out| self.read_output(out, tag, false));
+        let stderr = child
+            .stderr
+            .take()
+            .map(|err| self.read_output(err, tag, true));
+        let started = Instant::now();
+        let timed_out = || {
+            self.timeout
+                .as_ref()
+                .and_then(Deadline::limit)
+                .is_some_and(|limit| started.elapsed() >= limit)
+        };
+        let mut killed = false;
+        let exit_code = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    if let Some(timeout) = &self.timeout {
+                        timeout.record(started.elapsed());
+                    }
+                    // Like when the run is stopped, the processes it started go with it
+                    killed = status.signal().is_some();
+                    break status_code(status);
+                }
+                Ok(None) if timed_out() => {
+                    warn!("'{}' timed out, killing it", cmd);
+                    if let Err(err) = terminate(&mut child) {
+                        error!("could not kill command '{}': {}", cmd, err);
+                    }
+                    killed = true;
+                    break TIMEOUT_EXIT_CODE;
+                }
+                Ok(None) => thread::sleep(POLL_INTERVAL),
+                Err(err) => {
+                    error!("could not wait for command '{}': {}", cmd, err);
+                    break 1;
+                }
+            }
+        };
+        if killed {
+            kill_group(child.id());
+        }
+        self.children.remove(child.id());
+        // The readers report their own errors. Once killed, the output may still be held open by
+        // a process that left the group of the command
+        let join = |reader: Option<Receiver<(Vec<u8>, Option<Spool>)>>| {
+            let output = match reader {
+                Some(reader) if killed => reader.recv_timeout(KILL_GRACE_PERIOD),
+                Some(reader) => reader.recv().map_err(RecvTimeoutError::from),
+                None => return (Vec::new(), None),
+            };
+            output.unwrap_or_else(|_| {
+                warn!(
+                    "the output of '{}' is cut short by a process it started",
+                    cmd
+                );
+                (Vec::new(), None)
+            })
+        };
+        let (stdout, stdout_spool) = join(stdout);
+        let (stderr, stderr_spool) = join(stderr);
+        let output = Output {
+            stdout,
+            stderr,
+            stdout_spool,
+            stderr_spool,
+        };
+        (exit_code, output)
+    }
+
+    /// Run the command once for the slot and write the input of every job received to its stdin,
+    /// until there are no more jobs. Nothing is run if the run is stopped before the command starts
+    fn stream(
+        &self,
+        task: &Template,
+        jobs: &Receiver<Job>,
+        slot: usize,
+        stop: &AtomicBool,
+    ) -> Option<JobResult> {
+        if !self.wait_to_start(stop) {
+            return None;
+        }
+        let start = Local::now();
+        let job = Job {
+            index: slot - 1,
+            args: Vec::new(),
+            input: None,
+            copy: 0,
+        };
+        let host = self.host(&job, slot);
+        // The blocks are left to the other slots
+        let not_run = |cmd: String, exit_code: i32| JobResult {
+            seq: job.index,
+            args: job.args.clone(),
+            start,
+            duration: Local::now().signed_duration_since(start),
+            cmd,
+            exit_code,
+            attempts: 0,
+            host: host.map(String::from),
+            slot,
+            output: Output::default(),
+        };
+        let line = match self.prepare(task, &job, slot) {
+            Ok(line) => line,
+            Err(err) => {
+                error!(
+                    "could not expand '{}' for slot {}: {}",
+                    task.command, slot, err
+                );
+                return Some(not_run(task.command.clone(), 1));
+            }
+        };
+        let cmd = line.to_string();
+        if self.show_commands {
+            eprintln!("{}", cmd);
+        }
+        if self.dry_run {
+            return Some(not_run(cmd, 0));
+        }
+        let dir = match self.workdir(&job, slot) {
+            Ok(dir) => dir,
+            Err(err) => {
+                error!("could not find the directory of slot {}: {}", slot, err);
+                return Some(not_run(cmd, 1));
+            }
+        };
+        let mut child = match self
+            .command(&line, dir.as_deref(), host)
+            .stdin(Stdio::piped())
+            .spawn()
+        {
+            Err(err) => {
+                error!("could not execute command '{}': {}", cmd, err);
+                return Some(not_run(cmd, 1));
+            }
+            Ok(child) => child,
+        };
+        self.children.insert(child.id());
+        self.running.insert(slot, &cmd);
+        let mut stdin = child
+            .stdin
+            .take()
+            .expect("The stdin of the command is piped");
+        let mut lost_block = false;
+        for job in jobs.iter() {
+            if stop.load(Ordering::SeqCst) {
+                continue;
+            }
+            let input = job.input.unwrap_or_default();
+            if let Err(err) = stdin.write_all(&input) {
+                // The next blocks are left to the other commands
+                error!(
+                    "could not write block {} to '{}': {}",
+                    job.index + 1,
+                    cmd,
+                    err
+                );
+                lost_block = true;
+                break;
+            }
+        }
+        // Closing stdin lets the command end
+        drop(stdin);
+        let mut exit_code = match child.wait() {
+            Ok(status) => status_code(status),
+            Err(err) => {
+                error!("could not wait for command '{}': {}", cmd, err);
+                1
+            }
+        };
+        // A command that didn't get all its blocks failed, even if it succeeded
+        if lost_block && exit_code == 0 {
+            exit_code = 1;
+        }
+        self.children.remove(child.id());
+        self.running.remove(slot);
+        Some(JobResult {
+            seq: job.index,
+            args: job.args.clone(),
+            start,
+            duration: Local::now().signed_duration_since(start),
+            cmd,
+            exit_code,
+            attempts: 1,
+            host: host.map(String::from),
+            slot,
+            output: Output::default(),
+        })
+    }
+
+    /// Wait until the system can take another job, and then for the delay since the start of the
+    /// previous one. Returns false if the run is stopped first
+    fn wait_to_start(&self, stop: &AtomicBool) -> bool {
+        self.load.as_ref().map_or(true, |load| load.wait(stop))
+            && self
+                .memfree
+                .as_ref()
+                .map_or(true, |memfree| memfree.wait(stop))
+            && self.delay.as_ref().map_or(true, |delay| delay.wait(stop))
+    }
+
+    /// Run the job in the slot, or nothing if the run is stopped while the job waits to start or
+    /// if its command is declined
+    fn execute(
+        &self,
+        task: &Template,
+        job: Job,
+        slot: usize,
+        stop: &AtomicBool,
+    ) -> Option<JobResult> {
+        if !self.dry_run && !self.wait_to_start(stop) {
+            debug!("Not starting job {}, as the run is stopped", job.index + 1);
+            return None;
+        }
+        self.counters.started.fetch_add(1, Ordering::SeqCst);
+        self.counters.running.fetch_add(1, Ordering::SeqCst);
+        let result = self.execute_job(task, job, slot, stop);
+        self.counters.running.fetch_sub(1, Ordering::SeqCst);
+        match &result {
+            Some(result) => {
+                self.counters.completed.fetch_add(1, Ordering::SeqCst);
+                if result.exit_code != 0 {
+                    self.counters.failed.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            // A declined job never started
+            None => {
+                self.counters.started.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        result
+    }
+
+    /// Run the job in the slot, without retrying it once `stop` is set, or nothing if its command
+    /// is declined with --interactive
+    fn execute_job(
+        &self,
+        task: &Template,
+        job: Job,
+        slot: usize,
+        stop: &AtomicBool,
+    ) -> Option<JobResult> {
+        let host = self.host(&job, slot);
+        let not_run = |cmd: String, exit_code: i32| JobResult {
+            seq: job.index,
+            args: job.args.clone(),
+            start: Local::now(),
+            duration: Duration::zero(),
+            cmd,
+            exit_code,
+            attempts: 0,
+            host: host.map(String::from),
+            slot,
+            output: Output::default(),
+        };
+        let line = match self.prepare(task, &job, slot) {
+            Ok(line) => line,
+            Err(err) => {
+                error!(
+                    "could not expand '{}' for job {}: {}",
+                    task.command,
+                    job.index + 1,
+                    err
+                );
+                return Some(not_run(task.command.clone(), 1));
+            }
+        };
+        let cmd = line.to_string();
+        // The command asked about is the one run, as it is only expanded once
+        if !self.dry_run && !self.confirm(&cmd) {
+            debug!("Not running '{}'", cmd);
+            let _ = self.decline.send(job.index);
+            return None;
+        }
+        if self.show_commands {
+            eprintln!("{}", cmd);
+        }
+        if self.dry_run {
+            return Some(not_run(cmd, 0));
+        }
+        let start = Local::now();
+        let tag = match self
+            .tag
+            .as_ref()
+            .map(|tag| tag.substitute(&job, Some(slot)))
+        {
+            Some(Err(err)) => {
+                error!(
+                    "could not expand the tag for job {}: {}",
+                    job.index + 1,
+                    err
+                );
+                return Some(not_run(cmd, 1));
+            }
+            Some(Ok(tag)) => Some(tag),
+            None => None,
+        };
+        let dir = match self.workdir(&job, slot) {
+            Ok(dir) => dir,
+            Err(err) => {
+                error!(
+                    "could not find the directory of job {}: {}",
+                    job.index + 1,
+                    err
+                );
+                return Some(not_run(cmd, 1));
+            }
+        };
+        let (transfer, returns) = match host.map(|_| self.remote_files(&job, slot)) {
+            Some(Err(err)) => {
+                error!(
+                    "could not expand the files of job {}: {}",
+                    job.index + 1,
+                    err
+                );
+                return Some(not_run(cmd, 1));
+            }
+            Some(Ok(files)) => files,
+            None => (None, Vec::new()),
+        };
+        if let (Some(host), Some(file)) = (host, &transfer) {
+            let to = format!("{}:{}", host, file);
+            if let Err(err) = remote::run(remote::scp_command(file, &to)) {
+                error!("could not copy '{}' to {}: {}", file, host, err);
+                return Some(not_run(cmd, 1));
+            }
+        }
+        self.running.insert(slot, &cmd);
+        let mut attempts = 1;
+        let input = job.input.as_deref();
+        let dir = dir.as_deref();
+        let (mut exit_code, mut output) = self.run(&line, input, tag.as_deref(), dir, host);
+        while exit_code != 0 && attempts <= self.retries && !stop.load(Ordering::SeqCst) {
+            debug!("'{}' exited with status code {}, retrying", cmd, exit_code);
+            if let Some(backoff) = &self.backoff {
+                if sleep_unless_stopped(backoff.delay(attempts), stop) {
+                    break;
+                }
+            }
+            attempts += 1;
+            let (retry_code, retry_output) = self.run(&line, input, tag.as_deref(), dir, host);
+            exit_code = retry_code;
+            output = retry_output;
+        }
+        if let Some(host) = host {
+            // A job whose results are missing failed, even if the command succeeded
+            if !self.fetch(host, transfer.as_ref(), &returns) && exit_code == 0 {
+                exit_code = 1;
+            }
+        }
+        self.running.remove(slot);
+        let duration = Local::now().signed_duration_since(start);
+        Some(JobResult {
+            seq: job.index,
+            args: job.args.clone(),
+            start,
+            duration,
+            cmd,
+            exit_code,
+            attempts,
+            host: host.map(String::from),
+            slot,
+            output,
+        })
+    }
+}
+
+/// Spawn the worker threads. With `n == 0`, a new thread is spawned for every job. The workers
+/// don't start new jobs once `stop` is set
+///
+/// The result sender is taken by value and only the workers hold clones of it, so the result
+/// channel disconnects as soon as every worker is done.
+pub fn start_workers(
+    n: usize,
+    runner: &Arc<Runner>,
+    task: &Arc<Template>,
+    stop: &Arc<AtomicBool>,
+    jobs: Receiver<Job>,
+    results: Sender<JobResult>,
+) {
+    if n == 0 {
+        debug!("Starting one thread per job");
+        let runner = runner.clone();
+        let task = task.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            while let Ok(job) = jobs.recv() {
+                if stop.load(Ordering::SeqCst) {
+                    continue;
+                }
+                let results = results.clone();
+                let runner = runner.clone();
+                let task = task.clone();
+                // Each job has its own thread, so the slots are never reused
+                let slot = job.index + 1;
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    if let Some(result) = runner.execute(&task, job, slot, &stop) {
+                        results.send(result).unwrap();
+                    }
+                });
+            }
+        });
+        return;
+    }
+
+    debug!("Starting {} worker threads", n);
+    if runner.round_robin {
+        for slot in 1..=n {
+            let jobs = jobs.clone();
+            let results = results.clone();
+            let runner = runner.clone();
+            let task = task.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                if let Some(result) = runner.stream(&task, &jobs, slot, &stop) {
+                    results.send(result).unwrap();
+                }
+            });
+        }
+        return;
+    }
+
+    runner.pool.resize(n);
+    let runner = runner.clone();
+    let task = task.clone();
+    let stop = stop.clone();
+    // Start the workers as the pool grows, until every job was taken
+    thread::spawn(move || {
+        let mut state = runner.pool.state.lock().unwrap();
+        while !state.done {
+            while state.spawned < state.limit {
+                state.spawned += 1;
+                spawn_worker(
+                    state.spawned,
+                    runner.clone(),
+                    task.clone(),
+                    stop.clone(),
+                    jobs.clone(),
+                    results.clone(),
+                );
+            }
+            state = runner.pool.changed.wait(state).unwrap();
+        }
+    });
+}
+
+/// Run the jobs in the slot while it is within the limit of the pool
+fn spawn_worker(
+    slot: usize,
+    runner: Arc<Runner>,
+    task: Arc<Template>,
+    stop: Arc<AtomicBool>,
+    jobs: Receiver<Job>,
+    results: Sender<JobResult>,
+) {
+    thread::spawn(move || {
+        while runner.pool.wait(slot) {
+            let job = match jobs.recv() {
+                Ok(job) => job,
+                Err(_) => {
+                    runner.pool.finish();
+                    break;
+                }
+            };
+            // The queued jobs are dropped once stopped
+            if stop.load(Ordering::SeqCst) {
+                continue;
+            }
+            if let Some(result) = runner.execute(&task, job, slot, &stop) {
+                results.send(result).unwrap();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_backend() {
+        assert_eq!("ion".parse(), Ok(Backend::Ion));
+        assert_eq!("none".parse(), Ok(Backend::Exec));
+        assert_eq!("bash".parse(), Ok(Backend::Shell("bash".to_string())));
+        assert!("".parse::<Backend>().is_err());
+    }
+
+    /// Gives the readings in order, then the last one again
+    #[derive(Debug)]
+    struct Readings<T>(Mutex<Vec<T>>);
+
+    impl<T: Copy> Readings<T> {
+        fn new(readings: Vec<T>) -> Self {
+            Readings(Mutex::new(readings))
+        }
+
+        fn next(&self) -> Option<T> {
+            let mut readings = self.0.lock().unwrap();
+            if readings.len() > 1 {
+                Some(readings.remove(0))
+            } else {
+                readings.first().copied()
+            }
+        }
+    }
+
+    impl LoadSource for Readings<f64> {
+        fn load(&self) -> Option<f64> {
+            self.next()
+        }
+    }
+
+    impl MemorySource for Readings<usize> {
+        fn available(&self) -> Option<usize> {
+            self.next()
+        }
+    }
+
+    #[test]
+    fn waits_for_the_load_to_drop() {
+        let gate = LoadGate {
+            max: 2.,
+            source: Box::new(Readings::new(vec![4., 3., 2., 1.])),
+            poll: StdDuration::from_millis(10),
+        };
+        let stop = AtomicBool::new(false);
+        let start = Instant::now();
+        assert!(gate.wait(&stop));
+        assert!(start.elapsed() >= StdDuration::from_millis(20));
+        // The load stays low
+        let start = Instant::now();
+        assert!(gate.wait(&stop));
+        assert!(start.elapsed() < StdDuration::from_millis(10));
+    }
+
+    #[test]
+    fn stops_waiting_for_the_load_once_stopped() {
+        let gate = LoadGate {
+            max: 2.,
+            source: Box::new(Readings::new(vec![4.])),
+            poll: StdDuration::from_secs(10),
+        };
+        let stop = AtomicBool::new(true);
+        let start = Instant::now();
+        assert!(!gate.wait(&stop));
+        assert!(start.elapsed() < StdDuration::from_secs(1));
+    }
+
+    #[test]
+    fn waits_for_the_memory_to_be_freed() {
+        let gate = MemoryGate {
+            min: 1000,
+            source: Box::new(Readings::new(vec![10, 500, 2000])),
+            poll: StdDuration::from_millis(10),
+            max_poll: StdDuration::from_millis(15),
+        };
+        let stop = AtomicBool::new(false);
+        let start = Instant::now();
+        assert!(gate.wait(&stop));
+        // Backing off from 10ms to 15ms
+        assert!(start.elapsed() >= StdDuration::from_millis(25));
+        let start = Instant::now();
+        assert!(gate.wait(&stop));
+        assert!(start.elapsed() < StdDuration::from_millis(10));
+    }
+
+    #[test]
+    fn stops_waiting_for_the_memory_once_stopped() {
+        let gate = MemoryGate {
+            min: 1000,
+            source: Box::new(Readings::new(vec![10])),
+            poll: StdDuration::from_secs(10),
+            max_poll: StdDuration::from_secs(10),
+        };
+        let stop = AtomicBool::new(true);
+        let start = Instant::now();
+        assert!(!gate.wait(&stop));
+        assert!(start.elapsed() < StdDuration::from_secs(1));
+    }
+
+    #[test]
+    fn reads_the_available_memory() {
+        let meminfo = "MemTotal: 16318412 kB\nMemFree: 779836 kB\nMemAvailable: 8129720 kB\n";
+        assert_eq!(available_memory(meminfo), Some(8129720 * 1024));
+        assert_eq!(available_memory("MemTotal: 1 kB\n"), None);
+    }
+
+    #[test]
+    fn asks_on_the_terminal_before_running() {
+        let mut runner = Runner::new(&Config::default());
+        let terminal = io::Cursor::new(b"y\nmaybe\nn\na\n".to_vec());
+        runner.answers = Some(Mutex::new(Answers(Box::new(terminal))));
+        assert!(runner.confirm("echo a"));
+        assert!(!runner.confirm("echo b"));
+        assert!(runner.confirm("echo c"));
+        // Every next command runs without asking
+        assert!(runner.confirm("echo d"));
+    }
+
+    #[test]
+    fn skips_the_commands_once_the_terminal_is_closed() {
+        let mut runner = Runner::new(&Config::default());
+        let terminal = io::Cursor::new(b"y\n".to_vec());
+        runner.answers = Some(Mutex::new(Answers(Box::new(terminal))));
+        assert!(runner.confirm("echo a"));
+        assert!(!runner.confirm("echo b"));
+        assert!(!runner.confirm("echo c"));
+    }
+
+    #[test]
+    fn tags_each_line() {
+        assert_eq!(tag_lines("x\t", b"a\nb"), b"x\ta\nx\tb\n".to_vec());
+        assert!(tag_lines("x\t", b"").is_empty());
+    }
+
+    #[test]
+    fn the_retry_delay_grows_up_to_the_max() {
+        let backoff = Backoff {
+            delay: StdDuration::from_millis(100),
+            factor: 2.,
+            max: Some(StdDuration::from_millis(300)),
+        };
+        let delays: Vec<_> = (1..=4).map(|retry| backoff.delay(retry)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 300, 300]
+                .iter()
+                .map(|&ms| StdDuration::from_millis(ms))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn sleeps_until_stopped() {
+        let stop = AtomicBool::new(false);
+        let start = Instant::now();
+        assert!(!sleep_unless_stopped(StdDuration::from_millis(100), &stop));
+        assert!(start.elapsed() >= StdDuration::from_millis(100));
+        stop.store(true, Ordering::SeqCst);
+        let start = Instant::now();
+        assert!(sleep_unless_stopped(StdDuration::from_secs(10), &stop));
+        assert!(start.elapsed() < StdDuration::from_secs(1));
+    }
+
+    #[test]
+    fn the_jitter_spreads_the_starts_around_the_delay() {
+        let throttle = Throttle::new(
+            StdDuration::from_millis(100),
+            StdDuration::from_millis(50),
+            None,
+        );
+        let gaps = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..100).map(|_| throttle.gap(&mut rng)).collect::<Vec<_>>()
+        };
+        let drawn = gaps(7);
+        for gap in &drawn {
+            assert!(*gap >= StdDuration::from_millis(50), "{:?}", gap);
+            assert!(*gap <= StdDuration::from_millis(150), "{:?}", gap);
+        }
+        // They spread on both sides of the delay, the same way for the same seed
+        assert!(drawn.iter().any(|gap| *gap < StdDuration::from_millis(75)));
+        assert!(drawn.iter().any(|gap| *gap > StdDuration::from_millis(125)));
+        assert_eq!(drawn, gaps(7));
+        assert_ne!(drawn, gaps(8));
+    }
+
+    #[test]
+    fn the_throttle_spaces_out_the_starts() {
+        let throttle = Throttle::new(
+            StdDuration::from_millis(50),
+            StdDuration::from_secs(0),
+            None,
+        );
+        let stop = AtomicBool::new(false);
+        let start = Instant::now();
+        assert!((0..3).all(|_| throttle.wait(&stop)));
+        assert!(start.elapsed() >= StdDuration::from_millis(100));
+    }
+
+    #[test]
+    fn stops_waiting_for_the_throttle_once_stopped() {
+        let throttle = Throttle::new(StdDuration::from_secs(10), StdDuration::from_secs(0), None);
+        let stop = AtomicBool::new(false);
+        assert!(throttle.wait(&stop));
+        stop.store(true, Ordering::SeqCst);
+        let start = Instant::now();
+        assert!(!throttle.wait(&stop));
+        assert!(start.elapsed() < StdDuration::from_secs(1));
+    }
+
+    #[test]
+    fn the_hill_climb_settles_around_the_best_throughput() {
+        // Each job slows the others down once more than 6 run at once
+        let done = |limit: usize| (100 - (limit as isize - 6).pow(2)).max(0) as usize;
+        let second = StdDuration::from_secs(1);
+        let mut climb = HillClimb::new(2, 32);
+        let mut limit = 2;
+        let mut limits = Vec::new();
+        for _ in 0..30 {
+            limit = climb.observe(done(limit), second);
+            limits.push(limit);
+        }
+        assert!(
+            limits[10..].iter().all(|&limit| (5..=7).contains(&limit)),
+            "{:?}",
+            limits
+        );
+
+        let mut climb = HillClimb::new(1, 1);
+        assert_eq!(climb.observe(5, second), 1);
+        assert_eq!(climb.observe(5, second), 1);
+    }
+
+    #[test]
+    fn the_hill_climb_holds_until_enough_jobs_are_done() {
+        let second = StdDuration::from_secs(1);
+        let mut climb = HillClimb::new(2, 8);
+        assert_eq!(climb.observe(0, second), 2);
+        assert_eq!(climb.observe(1, second), 2);
+        assert_eq!(climb.observe(2, second), 3);
+        assert_eq!(climb.observe(0, second), 3);
+    }
+
+    #[test]
+    fn the_workers_above_the_limit_wait() {
+        let pool = Arc::new(Pool::default());
+        pool.resize(2);
+        assert!(pool.wait(2));
+        assert_eq!(pool.resize(0), 1);
+        let waiting = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.wait(2))
+        };
+        pool.resize(3);
+        assert!(waiting.join().unwrap());
+        pool.finish();
+        assert!(!pool.wait(1));
+    }
+
+    #[test]
+    fn the_median_timeout_waits_for_enough_jobs() {
+        let deadline = Deadline::new(Timeout::Median(2.));
+        deadline.record(StdDuration::from_secs(1));
+        deadline.record(StdDuration::from_secs(5));
+        assert_eq!(deadline.limit(), None);
+        deadline.record(StdDuration::from_secs(2));
+        assert_eq!(deadline.limit(), Some(StdDuration::from_secs(4)));
+        let fixed = Deadline::new(Timeout::Fixed(StdDuration::from_secs(3)));
+        fixed.record(StdDuration::from_secs(1));
+        assert_eq!(fixed.limit(), Some(StdDuration::from_secs(3)));
+    }
+
+    #[test]
+    fn killed_jobs_exit_with_128_plus_the_signal() {
+        assert_eq!(status_code(ExitStatus::from_raw(3 << 8)), 3);
+        assert_eq!(
+            status_code(ExitStatus::from_raw(libc::SIGKILL)),
+            128 + libc::SIGKILL
+        );
+    }
+}
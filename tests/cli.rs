@@ -0,0 +1,1472 @@
+use assert_cmd::Command;
+use predicates::prelude::*;
+
+/// The binary, running its commands with `sh` so that the tests don't need Ion installed
+fn parallel() -> Command {
+    let mut cmd = Command::cargo_bin("parallelion").unwrap();
+    cmd.args(&["--shell", "sh"]);
+    cmd
+}
+
+/// A path of its own under the temporary directory, removed with everything under it once dropped
+struct Scratch(std::path::PathBuf);
+
+fn scratch(name: &str) -> Scratch {
+    Scratch(std::env::temp_dir().join(format!("parallelion-{}-{}", name, std::process::id())))
+}
+
+impl std::ops::Deref for Scratch {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+impl AsRef<std::path::Path> for Scratch {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+impl AsRef<std::ffi::OsStr> for Scratch {
+    fn as_ref(&self) -> &std::ffi::OsStr {
+        self.0.as_os_str()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        // Whether the test made a directory or a file of it, if anything
+        let _ = std::fs::remove_dir_all(&self.0).or_else(|_| std::fs::remove_file(&self.0));
+    }
+}
+
+#[test]
+fn runs_the_inline_arguments() {
+    parallel()
+        .args(&["--keep-order", "echo {}", "a", "b", "c"])
+        .assert()
+        .success()
+        .stdout("a\nb\nc\n");
+}
+
+#[test]
+fn reads_the_arguments_from_stdin() {
+    parallel()
+        .args(&["--keep-order", "echo {}"])
+        .write_stdin("a\nb\nc\n")
+        .assert()
+        .success()
+        .stdout("a\nb\nc\n");
+}
+
+#[test]
+fn keep_order_buffer_spills_the_held_output() {
+    let tmpdir = scratch("spill");
+    std::fs::create_dir_all(&tmpdir).unwrap();
+    let args: Vec<String> = std::iter::once("1.5".to_string())
+        .chain((1..=8).map(|i| format!("0.0{}", i)))
+        .collect();
+    let child = std::process::Command::new(assert_cmd::cargo::cargo_bin("parallelion"))
+        .args(&["--shell", "sh", "--tmpdir"])
+        .arg(&tmpdir)
+        .args(&["-k", "--keep-order-buffer", "1", "-j", "4"])
+        .arg("sleep {}; echo {}; echo {} >&2")
+        .args(&args)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(800));
+    // The spools of the slow job and of the first result held back, and the spill file of the
+    // others instead of two spools each
+    let files = std::fs::read_dir(&tmpdir).unwrap().count();
+    let output = child.wait_with_output().unwrap();
+    assert!(files <= 5, "{} files", files);
+    assert!(output.status.success());
+    let expected: String = args.iter().map(|arg| format!("{}\n", arg)).collect();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), expected);
+    assert_eq!(std::fs::read_dir(&tmpdir).unwrap().count(), 0);
+}
+
+#[test]
+fn starts_the_jobs_before_the_end_of_stdin() {
+    use std::io::BufRead;
+    use std::process::Stdio;
+
+    for source in &[&[][..], &["::::", "-"][..]] {
+        let mut producer = std::process::Command::new("sh")
+            .args(&["-c", "echo first; sleep 3; echo second"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let started = std::time::Instant::now();
+        let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("parallelion"))
+            .args(&["--shell", "sh", "echo {}"])
+            .args(*source)
+            .stdin(producer.stdout.take().unwrap())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = std::io::BufReader::new(child.stdout.take().unwrap()).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), "first");
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+        assert_eq!(lines.next().unwrap().unwrap(), "second");
+        assert!(child.wait().unwrap().success());
+        producer.wait().unwrap();
+    }
+}
+
+#[test]
+fn dry_run_prints_the_commands_without_running_them() {
+    parallel()
+        .args(&[
+            "--dry-run",
+            "--keep-order",
+            "touch {}",
+            "/nonexistent/a",
+            "/nonexistent/b",
+        ])
+        .assert()
+        .success()
+        .stdout("touch /nonexistent/a\ntouch /nonexistent/b\n");
+}
+
+#[test]
+fn dry_run_leaves_no_joblog_nor_results() {
+    let dir = scratch("dry-run");
+    parallel()
+        .arg("--log")
+        .arg(dir.join("joblog"))
+        .arg("--results")
+        .arg(dir.join("results"))
+        .args(&["--dry-run", "echo {}", "a"])
+        .assert()
+        .success()
+        .stdout("echo a\n");
+    assert!(!dir.join("joblog").exists());
+    assert!(!dir.join("results").exists());
+}
+
+#[test]
+fn plan_shows_the_linked_arguments() {
+    parallel()
+        .args(&["--plan", "--link", "-j", "1", "echo {1} {2}"])
+        .args(&[":::", "a", "b", ":::", "1", "2"])
+        .assert()
+        .success()
+        .stdout(
+            "seq  slot  {1}  {2}  command\n\
+             1    1     a    1    echo a 1\n\
+             2    1     b    2    echo b 2\n",
+        );
+}
+
+#[test]
+fn null_output_ends_the_dry_run_commands_with_nul() {
+    parallel()
+        .args(&["--dry-run", "--null-output", "-k", "echo {}", "a b", "c"])
+        .assert()
+        .success()
+        .stdout("echo a b\0echo c\0");
+}
+
+#[test]
+fn exits_with_the_number_of_failed_jobs() {
+    parallel()
+        .args(&["exit {}", "0", "1", "2", "3"])
+        .assert()
+        .code(3);
+}
+
+#[test]
+fn a_single_job_slot_runs_the_jobs_in_order() {
+    let input: String = (1..=50).map(|i| format!("{}\n", i)).collect();
+    parallel()
+        .args(&["--jobs", "1", "echo {}"])
+        .write_stdin(input.clone())
+        .assert()
+        .success()
+        .stdout(input);
+}
+
+#[test]
+fn zero_jobs_runs_every_job_at_once() {
+    parallel()
+        .args(&["--jobs", "0", "--keep-order", "echo {}", "a", "b", "c"])
+        .assert()
+        .success()
+        .stdout("a\nb\nc\n");
+}
+
+#[test]
+fn finishes_when_stdin_is_empty() {
+    parallel()
+        .arg("echo {}")
+        .write_stdin("")
+        .timeout(std::time::Duration::from_secs(10))
+        .assert()
+        .success()
+        .stdout(predicate::str::is_empty());
+}
+
+/// The niceness of a job run with the given options, from the 19th field of its stat file
+#[cfg(target_os = "linux")]
+fn job_niceness(args: &[&str]) -> i32 {
+    let output = parallel()
+        .args(args)
+        .args(&["cut -d ' ' -f 19 /proc/self/stat", "x"])
+        .output()
+        .unwrap();
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn nice_lowers_the_priority_of_the_jobs() {
+    let niceness = job_niceness(&[]);
+    assert_eq!(job_niceness(&["--nice", "5"]), (niceness + 5).min(19));
+}
+
+#[test]
+fn runs_the_jobs_in_their_workdir() {
+    let dir = scratch("workdir");
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    let file = dir.join("sub").join("file");
+    let expected = std::fs::canonicalize(dir.join("sub")).unwrap();
+    parallel()
+        .args(&["--workdir", "{//}", "pwd"])
+        .arg(&file)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", expected.to_string_lossy()));
+}
+
+#[test]
+fn fails_the_jobs_whose_workdir_is_missing() {
+    parallel()
+        .args(&["--workdir", "/nonexistent/{}", "pwd", "a"])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains(
+            "'/nonexistent/a' isn't a directory",
+        ));
+}
+
+#[test]
+fn env_only_passes_the_named_variables() {
+    let run = |env: &str| {
+        let output = parallel()
+            .env("PARALLELION_TEST_VAR", "set")
+            .args(&["--env", env, "echo \"<$PARALLELION_TEST_VAR>\"", "x"])
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    assert_eq!(run("PARALLELION_TEST_VAR"), "<set>\n");
+    assert_eq!(run("PARALLELION_OTHER_VAR"), "<>\n");
+}
+
+#[test]
+fn env_underscore_passes_the_variables_set_since_record_env() {
+    let home = scratch("home");
+    std::fs::create_dir_all(&home).unwrap();
+    parallel()
+        .env("HOME", &home)
+        .env("PARALLELION_OLD_VAR", "old")
+        .arg("--record-env")
+        .assert()
+        .success();
+    let recorded = std::fs::read_to_string(home.join(".parallel").join("ignored_vars")).unwrap();
+    assert!(recorded.lines().any(|name| name == "PARALLELION_OLD_VAR"));
+    parallel()
+        .env("HOME", &home)
+        .env("PARALLELION_OLD_VAR", "old")
+        .env("PARALLELION_NEW_VAR", "new")
+        .args(&[
+            "--env",
+            "_",
+            "echo \"<$PARALLELION_OLD_VAR><$PARALLELION_NEW_VAR>\"",
+            "x",
+        ])
+        .assert()
+        .success()
+        .stdout("<><new>\n");
+}
+
+#[test]
+fn max_chars_fills_up_the_commands() {
+    parallel()
+        .args(&[
+            "--keep-order",
+            "--max-chars",
+            "12",
+            "echo {}",
+            "a",
+            "b",
+            "c",
+            "d",
+            "e",
+        ])
+        .assert()
+        .success()
+        .stdout("a b c d\ne\n");
+}
+
+#[test]
+fn max_chars_rejects_an_argument_too_long_to_fit() {
+    parallel()
+        .args(&["--max-chars", "12", "echo {}", "a", "too-long-to-fit"])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("longer than 12 characters"));
+}
+
+#[test]
+fn max_lines_groups_the_lines_of_input() {
+    parallel()
+        .args(&["--keep-order", "-L", "2", "echo {}"])
+        .write_stdin("1\n2\n3\n4\n5\n6\n")
+        .assert()
+        .success()
+        .stdout("1 2\n3 4\n5 6\n");
+}
+
+/// A directory of fake commands, each a shell script, and a PATH finding them first
+fn shims(name: &str, scripts: &[(&str, &str)]) -> (Scratch, std::ffi::OsString) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = scratch(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    for (command, script) in scripts {
+        let path = dir.join(command);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    let path = std::env::join_paths(
+        std::iter::once(dir.to_path_buf())
+            .chain(std::env::split_paths(&std::env::var_os("PATH").unwrap())),
+    )
+    .unwrap();
+    (dir, path)
+}
+
+#[test]
+fn sshlogin_runs_the_jobs_over_ssh() {
+    let (_dir, path) = shims("ssh", &[("ssh", r#"echo "$1: $2""#)]);
+    parallel()
+        .env("PATH", path)
+        .args(&["-k", "-j", "1", "-S", ":", "-S", "user@remote", "echo {}"])
+        .args(&["a", "b", "c", "d"])
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match(r"\A((user@remote: echo )?[a-d]\n){4}\z").unwrap());
+}
+
+#[test]
+fn sshloginfile_gives_each_host_its_slots() {
+    let (dir, path) = shims("slf", &[("ssh", r#"echo "$1: $2""#)]);
+    let file = dir.join("sshlogins");
+    std::fs::write(&file, "# two slots, then one\n2/first\n1/second\n").unwrap();
+    parallel()
+        .env("PATH", path)
+        .args(&["-j", "5", "--slf"])
+        .arg(&file)
+        .args(&["echo {%}", "a", "b", "c", "d", "e", "f"])
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match(r"\A((first: echo [12]|second: echo 3)\n){6}\z").unwrap());
+}
+
+#[test]
+fn transfers_and_returns_the_files_around_remote_jobs() {
+    let log = scratch("trc-log");
+    let (_dir, path) = shims(
+        "trc",
+        &[
+            ("ssh", r#"echo "ssh $*" >> "$PARALLELION_LOG""#),
+            ("scp", r#"echo "scp $*" >> "$PARALLELION_LOG""#),
+        ],
+    );
+    parallel()
+        .env("PATH", path)
+        .env("PARALLELION_LOG", &log)
+        .args(&[
+            "-S",
+            "host",
+            "--transfer",
+            "--return",
+            "{.}.out",
+            "--cleanup",
+        ])
+        .args(&["wc {}", "in.txt"])
+        .assert()
+        .success();
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "scp in.txt host:in.txt\n\
+         ssh host wc in.txt\n\
+         scp host:in.out in.out\n\
+         ssh host rm -f -- 'in.txt' 'in.out'\n"
+    );
+}
+
+#[test]
+fn header_names_the_columns() {
+    parallel()
+        .args(&[
+            "-k",
+            "--header",
+            ":",
+            "--colsep",
+            ",",
+            "echo {name} is {age}",
+        ])
+        .write_stdin("name,age\nAnn,31\nBob,42\n")
+        .assert()
+        .success()
+        .stdout("Ann is 31\nBob is 42\n");
+}
+
+#[test]
+fn header_rejects_unknown_columns() {
+    parallel()
+        .args(&["--header", ":", "--colsep", ",", "echo {nmae}"])
+        .write_stdin("name,age\nAnn,31\n")
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains(
+            "{nmae} isn't a column of the header, which has name, age",
+        ));
+}
+
+#[test]
+fn rpl_defines_a_token_from_an_ion_expression() {
+    parallel()
+        .args(&[
+            "-k",
+            "--rpl",
+            "{upper} $to_uppercase($arg)",
+            "echo {upper}",
+            "a",
+            "b",
+        ])
+        .assert()
+        .success()
+        .stdout("A\nB\n");
+}
+
+/// Run the binary in a new session, with a new terminal as its controlling terminal and `typed`
+/// already typed on it
+fn parallel_on_a_terminal(args: &[&str], typed: &[u8]) -> std::process::Output {
+    use std::io::Write;
+    use std::os::unix::{io::FromRawFd, process::CommandExt};
+    let (mut master, mut slave) = (0, 0);
+    let null = std::ptr::null_mut();
+    // Safe, as the descriptors are owned by the files right after
+    let opened = unsafe { libc::openpty(&mut master, &mut slave, null, null as _, null as _) };
+    assert_eq!(opened, 0);
+    let (mut master, slave) = unsafe {
+        (
+            std::fs::File::from_raw_fd(master),
+            std::fs::File::from_raw_fd(slave),
+        )
+    };
+    master.write_all(typed).unwrap();
+    let mut cmd = std::process::Command::new(assert_cmd::cargo::cargo_bin("parallelion"));
+    cmd.args(&["--shell", "sh"]).args(args);
+    let tty = std::os::unix::io::AsRawFd::as_raw_fd(&slave);
+    // Safe, as only async-signal-safe functions are called between the fork and the exec
+    unsafe {
+        cmd.pre_exec(move || {
+            match libc::setsid() != -1 && libc::ioctl(tty, libc::TIOCSCTTY, 0) != -1 {
+                true => Ok(()),
+                false => Err(std::io::Error::last_os_error()),
+            }
+        });
+    }
+    let output = cmd.stdin(std::process::Stdio::null()).output().unwrap();
+    drop((master, slave));
+    output
+}
+
+#[test]
+fn interactive_asks_before_each_command() {
+    let log = scratch("ask");
+    let output = parallel_on_a_terminal(
+        &[
+            "--log",
+            log.to_str().unwrap(),
+            "-j",
+            "1",
+            "-k",
+            "--interactive",
+            "echo {} {%}",
+            "a",
+            "b",
+            "c",
+            "d",
+        ],
+        b"y\nn\na\n",
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"a 1\nc 1\nd 1\n");
+    assert!(stderr.contains("Do 'echo a 1'?"));
+    assert!(stderr.contains("Do 'echo b 1'?"));
+    assert!(stderr.contains("Do 'echo c 1'?"));
+    assert!(!stderr.contains("Do 'echo d 1'?"));
+    // The declined job isn't recorded, so that --resume runs it
+    let mut seqs: Vec<_> = joblog_records(&log)
+        .iter()
+        .map(|record| record["seq"].as_u64().unwrap())
+        .collect();
+    seqs.sort();
+    assert_eq!(seqs, [0, 2, 3]);
+}
+
+#[test]
+fn interactive_skips_the_commands_once_the_terminal_is_closed() {
+    let log = scratch("closed");
+    let output = parallel_on_a_terminal(
+        &[
+            "--log",
+            log.to_str().unwrap(),
+            "-j",
+            "1",
+            "-k",
+            "--interactive",
+            "echo {}",
+            "a",
+            "b",
+        ],
+        b"y\n\x04",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(output.stdout, b"a\n");
+    let records = joblog_records(&log);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["seq"], 0);
+}
+
+#[test]
+fn interactive_fails_without_a_terminal() {
+    use std::os::unix::process::CommandExt;
+    let log = scratch("no-tty");
+    let mut cmd = std::process::Command::new(assert_cmd::cargo::cargo_bin("parallelion"));
+    cmd.args(&["--shell", "sh", "--log"])
+        .arg(&log)
+        .args(&["--interactive", "echo {}", "a"]);
+    // Safe, as only async-signal-safe functions are called between the fork and the exec
+    unsafe {
+        // A new session has no controlling terminal
+        cmd.pre_exec(|| match libc::setsid() {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    let output = cmd.stdin(std::process::Stdio::null()).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(!log.exists());
+}
+
+#[test]
+fn jobs_read_an_empty_stdin_unless_kept() {
+    parallel()
+        .args(&["cat; echo {}", "a"])
+        .write_stdin("not for the job\n")
+        .timeout(std::time::Duration::from_secs(10))
+        .assert()
+        .success()
+        .stdout("a\n");
+    parallel()
+        .args(&["--keep-stdin", "cat; echo {}", "a"])
+        .write_stdin("for the job\n")
+        .timeout(std::time::Duration::from_secs(10))
+        .assert()
+        .success()
+        .stdout("for the job\na\n");
+}
+
+#[test]
+fn spools_the_output_in_the_tmpdir() {
+    let dir = scratch("tmpdir");
+    std::fs::create_dir_all(&dir).unwrap();
+    // The job sees the files its output is spooled to
+    parallel()
+        .arg("--tmpdir")
+        .arg(&dir)
+        .arg("ls {}")
+        .arg(&dir)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("parallelion-"));
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+}
+
+#[test]
+fn files_prints_the_paths_of_the_outputs() {
+    let dir = scratch("files");
+    std::fs::create_dir_all(&dir).unwrap();
+    let output = parallel()
+        .arg("--tmpdir")
+        .arg(&dir)
+        .args(&["-k", "--files", "echo {}", "a", "b"])
+        .output()
+        .unwrap();
+    let outputs: Vec<_> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|path| std::fs::read_to_string(path).unwrap())
+        .collect();
+    assert!(output.status.success());
+    assert_eq!(outputs, vec!["a\n", "b\n"]);
+}
+
+#[test]
+fn progress_format_json_writes_the_counters() {
+    let output = parallel()
+        .args(&["--progress-format", "json", "-j", "2", "sleep 0.{}"])
+        .args(&["1", "2", "3", "4", "5", "6"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let records: Vec<serde_json::Value> = String::from_utf8(output.stderr)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let completed: Vec<_> = records
+        .iter()
+        .map(|record| record["completed"].as_u64().unwrap())
+        .collect();
+    assert!(completed.len() > 1);
+    assert!(completed.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert_eq!(completed.last(), Some(&6));
+    assert_eq!(records.last().unwrap()["failed"], 0);
+}
+
+#[test]
+fn progress_file_gets_the_bar_updates() {
+    let path = scratch("progress");
+    parallel()
+        .arg("--progress-file")
+        .arg(&path)
+        .args(&["-j", "2", "sleep 0.{}", "1", "2", "3", "4", "5", "6"])
+        .assert()
+        .success()
+        .stderr("");
+    let progress = std::fs::read_to_string(&path).unwrap();
+    assert!(progress.lines().count() > 1);
+    assert!(progress.lines().last().unwrap().starts_with("6/6 done"));
+}
+
+#[test]
+fn total_jobs_is_the_end_of_the_progress_of_stdin() {
+    let path = scratch("total");
+    for &(total, done) in &[(Some("4"), "4/4 done"), (None, "4 done")] {
+        let mut cmd = parallel();
+        cmd.arg("--progress-file").arg(&path);
+        if let Some(total) = total {
+            cmd.args(&["--total-jobs", total]);
+        }
+        cmd.arg("true")
+            .write_stdin("a\nb\nc\nd\n")
+            .assert()
+            .success();
+        let progress = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            progress.lines().last().unwrap().starts_with(done),
+            "{}",
+            progress
+        );
+    }
+}
+
+#[test]
+fn progress_interval_holds_back_the_updates() {
+    let path = scratch("interval");
+    parallel()
+        .arg("--progress-file")
+        .arg(&path)
+        .args(&["--progress-interval", "1h", "-j", "2", "sleep 0.{}"])
+        .args(&["1", "2", "3", "4", "5", "6"])
+        .assert()
+        .success();
+    let progress = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = progress.lines().collect();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with("6/6 done"));
+}
+
+#[test]
+fn warns_about_the_argument_between_single_quotes() {
+    parallel()
+        .args(&["echo '{}'", "it is"])
+        .assert()
+        .success()
+        .stdout("it is\n")
+        .stderr(predicate::str::contains("between single quotes"));
+    parallel()
+        .args(&["--quote", "echo '{}'", "it is"])
+        .assert()
+        .stderr(predicate::str::contains(
+            "which --quote already adds: remove them",
+        ))
+        .stderr(predicate::str::contains("use --quote instead").not());
+    parallel()
+        .args(&["--no-warn", "echo '{}'", "it is"])
+        .assert()
+        .success()
+        .stderr("");
+}
+
+#[test]
+fn shellquote_prints_the_quoted_arguments() {
+    parallel()
+        .args(&["--shellquote", ":::", "a b", "it's", "$HOME", "\"*\""])
+        .assert()
+        .success()
+        .stdout("'a b'\n'it'\\''s'\n'$HOME'\n'\"*\"'\n");
+    parallel()
+        .arg("--shellquote")
+        .write_stdin("x;y\n`z`\n")
+        .assert()
+        .success()
+        .stdout("'x;y'\n'`z`'\n");
+}
+
+#[test]
+fn quote_passes_an_argument_as_a_single_word() {
+    let dir = scratch("quote");
+    std::fs::create_dir_all(dir.join("x")).unwrap();
+    parallel()
+        .current_dir(&dir)
+        .args(&["--quote", "printf '<%s>\\n' {}", "a b; rm -rf x"])
+        .assert()
+        .success()
+        .stdout("<a b; rm -rf x>\n");
+    assert!(dir.join("x").is_dir());
+}
+
+#[test]
+fn rejects_an_empty_command() {
+    parallel()
+        .args(&["  ", "a"])
+        .assert()
+        .failure()
+        .code(1)
+        .stderr(predicate::str::contains("The command is empty"));
+}
+
+#[test]
+fn runs_the_blank_arguments_unless_no_run_if_empty() {
+    parallel()
+        .args(&["-k", "echo \"<{}>\""])
+        .write_stdin("a\n\n  \nb\n")
+        .assert()
+        .success()
+        .stdout("<a>\n<>\n<  >\n<b>\n");
+    parallel()
+        .args(&["-k", "-r", "echo \"<{}>\""])
+        .write_stdin("a\n\n  \nb\n")
+        .assert()
+        .success()
+        .stdout("<a>\n<b>\n");
+}
+
+#[test]
+fn colsep_substitutes_the_column_ranges() {
+    parallel()
+        .args(&["--colsep", ",", "echo {2..}/{..3}/{-1}", "a,b,c,d"])
+        .assert()
+        .success()
+        .stdout("b c d/a b c/d\n");
+}
+
+#[test]
+fn onall_runs_the_copies_of_each_job() {
+    parallel()
+        .args(&["-k", "--onall", "--copies", "3", "echo {}", "a", "b"])
+        .assert()
+        .success()
+        .stdout("a\na\na\nb\nb\nb\n");
+    parallel()
+        .args(&["--onall", "echo {}", "a"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--onall requires"));
+}
+
+#[test]
+fn onall_runs_each_job_on_every_host() {
+    let (_dir, path) = shims("onall", &[("ssh", r#"echo "$1: $2""#)]);
+    parallel()
+        .env("PATH", path)
+        .args(&[
+            "-k", "--onall", "-S", "first", "-S", "second", "echo {}", "a", "b",
+        ])
+        .assert()
+        .success()
+        .stdout("first: echo a\nsecond: echo a\nfirst: echo b\nsecond: echo b\n");
+}
+
+#[test]
+fn summary_csv_has_a_row_per_job() {
+    let csv = scratch("summary");
+    parallel()
+        .arg("--summary-csv")
+        .arg(&csv)
+        .args(&["exit {}", "0", "3", "0"])
+        .assert()
+        .code(1);
+    let summary = std::fs::read_to_string(&csv).unwrap();
+    let mut lines = summary.lines();
+    assert_eq!(lines.next(), Some("seq,arg,exit_code,duration_seconds"));
+    let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[1][..3], ["2", "3", "3"]);
+    assert!(rows[1][3].parse::<f64>().is_ok());
+}
+
+#[test]
+fn joblog_format_tsv_writes_the_columns_of_gnu_parallel() {
+    let log = scratch("tsv");
+    parallel()
+        .arg("--log")
+        .arg(&log)
+        .args(&["--joblog-format", "tsv", "exit {}", "3"])
+        .assert()
+        .code(1);
+    let joblog = std::fs::read_to_string(&log).unwrap();
+    let mut lines = joblog.lines();
+    assert_eq!(
+        lines.next(),
+        Some("Seq\tHost\tStarttime\tJobRuntime\tSend\tReceive\tExitval\tSignal\tCommand")
+    );
+    let row: Vec<&str> = lines.next().unwrap().split('\t').map(str::trim).collect();
+    assert_eq!(row[0], "1");
+    assert_eq!(row[1], ":");
+    assert!(row[2].parse::<f64>().unwrap() > 0.);
+    assert!(row[3].parse::<f64>().unwrap() >= 0.);
+    assert_eq!(&row[4..], &["0", "0", "3", "0", "exit 3"]);
+    assert_eq!(lines.next(), None);
+}
+
+#[test]
+fn a_relative_timeout_kills_the_outliers() {
+    let started = std::time::Instant::now();
+    parallel()
+        .args(&["-k", "-j", "1", "--timeout", "500%", "sleep {} && echo {}"])
+        .args(&["0.1", "0.1", "0.1", "0.1", "10", "0.1"])
+        .assert()
+        .code(1)
+        .stdout("0.1\n0.1\n0.1\n0.1\n0.1\n")
+        .stderr(predicate::str::contains("timed out"));
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn halt_on_error_records_the_running_jobs() {
+    let log = scratch("halt");
+    let started = std::time::Instant::now();
+    parallel()
+        .arg("--log")
+        .arg(&log)
+        .args(&["-j", "3", "--halt-on-error", "{}"])
+        .args(&[
+            "sleep 0.2; exit 1",
+            "sleep 10; true",
+            "sleep 10; true",
+            "sleep 10",
+        ])
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains(
+            "1 job(s) completed before, 2 while halting",
+        ));
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    let joblog = std::fs::read_to_string(&log).unwrap();
+    let mut seqs: Vec<&str> = joblog
+        .lines()
+        .map(|line| line.split(',').next().unwrap())
+        .collect();
+    seqs.sort();
+    assert_eq!(seqs, ["{\"seq\":0", "{\"seq\":1", "{\"seq\":2"]);
+}
+
+#[test]
+fn retry_backoff_waits_longer_each_time() {
+    let file = scratch("retry");
+    parallel()
+        .args(&[
+            "--retries",
+            "2",
+            "--retry-delay",
+            "0.2s",
+            "--retry-backoff",
+            "3",
+        ])
+        .arg(format!(
+            "date +%s.%N >> {0}; [ $(wc -l < {0}) -ge 3 ]",
+            file.display()
+        ))
+        .arg("x")
+        .assert()
+        .success();
+    let attempts: Vec<f64> = std::fs::read_to_string(&file)
+        .unwrap()
+        .lines()
+        .map(|line| line.parse().unwrap())
+        .collect();
+    assert_eq!(attempts.len(), 3);
+    let (first, second) = (attempts[1] - attempts[0], attempts[2] - attempts[1]);
+    assert!(first >= 0.2, "{:?}", attempts);
+    assert!(second >= 0.6 && second > first, "{:?}", attempts);
+}
+
+#[test]
+fn total_timeout_stops_the_whole_run() {
+    let started = std::time::Instant::now();
+    parallel()
+        .args(&["-j", "2", "--total-timeout", "0.5s", "sleep {}; true"])
+        .args(&["10"; 8])
+        .assert()
+        .code(124)
+        .stderr(predicate::str::contains("total timeout"));
+    assert!(started.elapsed() < std::time::Duration::from_secs(3));
+}
+
+#[test]
+fn total_timeout_kills_the_processes_started_by_the_jobs() {
+    let dir = scratch("orphan");
+    std::fs::create_dir_all(&dir).unwrap();
+    parallel()
+        .args(&["-j", "2", "--total-timeout", "0.5s", "sleep 1; touch {}"])
+        .arg(dir.join("a"))
+        .arg(dir.join("b"))
+        .assert()
+        .code(124);
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    assert!(!dir.join("a").exists());
+    assert!(!dir.join("b").exists());
+}
+
+#[test]
+fn sigusr2_runs_more_jobs_at_once() {
+    let started = std::time::Instant::now();
+    let child = std::process::Command::new(assert_cmd::cargo::cargo_bin("parallelion"))
+        .args(&[
+            "--shell",
+            "sh",
+            "-j",
+            "1",
+            "sleep 1; echo {%}",
+            "a",
+            "b",
+            "c",
+            "d",
+        ])
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    for _ in 0..2 {
+        let status = std::process::Command::new("kill")
+            .args(&["-USR2", &child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        // The same signal received twice before it is handled only counts once
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let mut slots: Vec<_> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect();
+    slots.sort();
+    slots.dedup();
+    assert_eq!(slots, vec!["1", "2", "3"]);
+    assert!(started.elapsed() < std::time::Duration::from_millis(3500));
+}
+
+#[test]
+fn sigusr1_runs_less_jobs_at_once() {
+    let started = std::time::Instant::now();
+    let child = std::process::Command::new(assert_cmd::cargo::cargo_bin("parallelion"))
+        .args(&["--shell", "sh", "-j", "2", "sleep 1", "a", "b", "c", "d"])
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    let status = std::process::Command::new("kill")
+        .args(&["-USR1", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    // The last two jobs run one after the other
+    assert!(started.elapsed() >= std::time::Duration::from_millis(2800));
+}
+
+#[test]
+fn sigusr1_prints_the_running_jobs() {
+    let child = std::process::Command::new(assert_cmd::cargo::cargo_bin("parallelion"))
+        .args(&["--shell", "sh", "-j", "2", "sleep 1; echo {}", "a", "b"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    let status = std::process::Command::new("kill")
+        .args(&["-USR1", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("2 job(s) running:\n"));
+    assert!(
+        predicate::str::is_match(r"(?m)^[12]\t0\.\ds\tsleep 1; echo a$")
+            .unwrap()
+            .eval(&stderr)
+    );
+    assert!(stderr.contains("\tsleep 1; echo b\n"));
+}
+
+#[test]
+fn semaphore_limits_the_separate_runs() {
+    let id = format!("test-{}", std::process::id());
+    let started = std::time::Instant::now();
+    let children: Vec<_> = (0..4)
+        .map(|_| {
+            std::process::Command::new(assert_cmd::cargo::cargo_bin("parallelion"))
+                .args(&["--shell", "sh", "--semaphore", "--id", &id, "-j", "2"])
+                .arg("sleep 0.5")
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+    // Two at a time, so they can't all be done after the first half second
+    let elapsed = started.elapsed();
+    assert!(elapsed >= std::time::Duration::from_millis(1000));
+    assert!(elapsed < std::time::Duration::from_millis(1900));
+    parallel()
+        .args(&["--semaphore", "--id", &id, "--wait"])
+        .assert()
+        .success();
+}
+
+#[test]
+fn semaphore_fg_waits_and_bg_returns_right_away() {
+    let dir = scratch("bg");
+    std::fs::create_dir_all(&dir).unwrap();
+    let id = format!("bg-{}", std::process::id());
+    parallel()
+        .current_dir(&dir)
+        .args(&["--semaphore", "--id", &id, "--fg", "sleep 0.5; touch fg"])
+        .assert()
+        .success();
+    assert!(dir.join("fg").exists());
+
+    let started = std::time::Instant::now();
+    parallel()
+        .current_dir(&dir)
+        .args(&["--semaphore", "--id", &id, "--bg", "sleep 1; touch bg"])
+        .assert()
+        .success();
+    assert!(started.elapsed() < std::time::Duration::from_millis(800));
+    assert!(!dir.join("bg").exists());
+    // The command holds its slot by the time --bg returns
+    parallel()
+        .args(&["--semaphore", "--id", &id, "--wait"])
+        .assert()
+        .success();
+    assert!(dir.join("bg").exists());
+
+    // A --bg of the command is left to it
+    parallel()
+        .current_dir(&dir)
+        .args(&[
+            "--semaphore",
+            "--id",
+            &id,
+            "--bg",
+            "--",
+            "echo",
+            "--bg",
+            ">",
+            "words",
+        ])
+        .assert()
+        .success();
+    parallel()
+        .args(&["--semaphore", "--id", &id, "--wait"])
+        .assert()
+        .success();
+    assert_eq!(
+        std::fs::read_to_string(dir.join("words")).unwrap(),
+        "--bg\n"
+    );
+}
+
+#[test]
+fn compress_round_trips_the_output() {
+    let expected: String = (1..=20000).map(|i| format!("{}\n", i)).collect();
+    parallel()
+        .args(&["--compress", "seq {}", "20000"])
+        .assert()
+        .success()
+        .stdout(expected);
+    let dir = scratch("compress");
+    parallel()
+        .arg("--results")
+        .arg(&dir)
+        .args(&["--compress", "seq {}", "1000"])
+        .assert()
+        .success();
+    let stdout = std::fs::read(dir.join("0").join("stdout.gz")).unwrap();
+    assert_eq!(&stdout[..2], &[0x1f, 0x8b]);
+}
+
+#[test]
+fn output_prefix_numbers_the_lines_of_each_job() {
+    parallel()
+        .args(&["-k", "--output-prefix", "printf '%s\\n' {}1 {}2", "a", "b"])
+        .assert()
+        .success()
+        .stdout("1:a1\n1:a2\n2:b1\n2:b2\n");
+}
+
+#[test]
+fn two_runs_append_to_the_same_joblog() {
+    let log = scratch("append");
+    let appended = format!("+{}", log.to_str().unwrap());
+    let runs: Vec<_> = (0..2)
+        .map(|_| {
+            std::process::Command::new(assert_cmd::cargo::cargo_bin("parallelion"))
+                .args(&["--shell", "sh", "-j", "4", "--joblog", &appended, "echo {}"])
+                .args((1..=20).map(|i| i.to_string()))
+                .stdout(std::process::Stdio::null())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for mut run in runs {
+        assert!(run.wait().unwrap().success());
+    }
+    assert_eq!(joblog_records(&log).len(), 40);
+}
+
+#[test]
+fn version_reports_the_backends_and_the_cores() {
+    parallel()
+        .arg("--version")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(format!(
+            "parallelion {}\n",
+            env!("CARGO_PKG_VERSION")
+        )))
+        .stdout(predicate::str::contains(
+            "backends: ion (built in), none (built in), sh (external), ssh (external)\n",
+        ))
+        .stdout(predicate::str::contains(format!(
+            "cores: {}\n",
+            num_cpus::get()
+        )));
+}
+
+#[test]
+fn stdin_input_writes_each_argument_to_the_stdin_of_its_command() {
+    parallel()
+        .args(&["-k", "--stdin-input", "wc -c"])
+        .write_stdin("a\nbcd\n\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match(r"^\s*2\n\s*4\n\s*1\n$").unwrap());
+}
+
+/// Run `parallelion` and interrupt it once the first jobs started
+fn interrupt(args: &[&str]) -> (std::process::Output, std::time::Duration) {
+    let started = std::time::Instant::now();
+    let child = std::process::Command::new(assert_cmd::cargo::cargo_bin("parallelion"))
+        .args(&["--shell", "sh"])
+        .args(args)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    let status = std::process::Command::new("kill")
+        .args(&["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let output = child.wait_with_output().unwrap();
+    (output, started.elapsed())
+}
+
+#[test]
+fn on_interrupt_kill_stops_the_running_jobs() {
+    let (output, elapsed) = interrupt(&[
+        "--on-interrupt",
+        "kill",
+        "-j",
+        "2",
+        "sleep 5; echo {}",
+        "a",
+        "b",
+    ]);
+    assert_eq!(output.status.code(), Some(130));
+    assert_eq!(output.stdout, b"");
+    assert!(elapsed < std::time::Duration::from_secs(3));
+}
+
+#[test]
+fn on_interrupt_drain_lets_the_running_jobs_finish() {
+    let (output, _) = interrupt(&[
+        "--on-interrupt",
+        "drain",
+        "-j",
+        "1",
+        "sleep 1; echo {}",
+        "a",
+        "b",
+    ]);
+    assert_eq!(output.status.code(), Some(130));
+    assert_eq!(output.stdout, b"a\n");
+}
+
+#[test]
+fn results_format_names_the_files_of_each_job() {
+    for &(format, paths) in &[
+        ("tree", &["0/stdout", "0/stderr", "0/meta", "1/stdout"]),
+        ("flat", &["0.out", "0.err", "0.meta", "1.out"]),
+        (
+            "by-arg",
+            &["a-b/stdout", "a-b/stderr", "a-b/meta", "c.txt/stdout"],
+        ),
+    ] {
+        let dir = scratch(&format!("results-{}", format));
+        parallel()
+            .arg("--results")
+            .arg(&dir)
+            .args(&["--results-format", format, "echo {}", "a-b", "c.txt"])
+            .assert()
+            .success();
+        for path in paths {
+            assert!(dir.join(path).is_file(), "{} with {}", path, format);
+        }
+        let stdout = std::fs::read_to_string(dir.join(paths[0])).unwrap();
+        assert_eq!(stdout, "a-b\n");
+    }
+}
+
+#[test]
+fn timeout_kills_the_processes_started_by_the_job() {
+    let started = std::time::Instant::now();
+    parallel()
+        .args(&["--timeout", "1", "sleep 10; echo {}", "a"])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr(predicate::str::contains("timed out"));
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+}
+
+/// The records of the joblog at `path`
+fn joblog_records(path: &std::path::Path) -> Vec<serde_json::Value> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn each_job_gets_its_own_seq() {
+    let log = scratch("seq");
+    parallel()
+        .arg("--log")
+        .arg(&log)
+        .args(&["-j", "2", "echo {}", "a", "b", "c", "d", "e"])
+        .assert()
+        .success();
+    let mut seqs: Vec<u64> = joblog_records(&log)
+        .iter()
+        .map(|record| record["seq"].as_u64().unwrap())
+        .collect();
+    seqs.sort();
+    assert_eq!(seqs, [0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn exits_once_every_worker_is_done() {
+    parallel()
+        .args(&["-j", "4", "-k", "echo {}", "a", "b", "c", "d", "e", "f"])
+        .timeout(std::time::Duration::from_secs(10))
+        .assert()
+        .success()
+        .stdout("a\nb\nc\nd\ne\nf\n");
+}
+
+#[test]
+fn records_the_jobs_killed_by_a_signal_as_128_plus_the_signal() {
+    let log = scratch("signal");
+    parallel()
+        .arg("--log")
+        .arg(&log)
+        .args(&["kill -SEGV $$", ":::", "x"])
+        .assert()
+        .code(1);
+    let records = joblog_records(&log);
+    assert_eq!(records[0]["exit_code"], 128 + libc::SIGSEGV);
+}
+
+#[test]
+fn records_how_long_each_job_took() {
+    let log = scratch("took");
+    parallel()
+        .arg("--log")
+        .arg(&log)
+        .args(&["sleep {}", "1"])
+        .assert()
+        .success();
+    let records = joblog_records(&log);
+    let duration = records[0]["duration"].as_f64().unwrap();
+    assert!(duration >= 1. && duration < 3., "{}", duration);
+}
+
+#[test]
+fn resume_runs_the_jobs_missing_from_the_joblog() {
+    let log = scratch("resume");
+    parallel()
+        .arg("--log")
+        .arg(&log)
+        .args(&["echo {}", "a", "b"])
+        .assert()
+        .success();
+    parallel()
+        .arg("--log")
+        .arg(&log)
+        .args(&["--resume", "-k", "echo {}", "a", "b", "c", "d"])
+        .assert()
+        .success()
+        .stdout("c\nd\n");
+    assert_eq!(joblog_records(&log).len(), 4);
+}
+
+#[test]
+fn resume_failed_runs_the_failed_jobs_again() {
+    let log = scratch("resume-failed");
+    parallel()
+        .arg("--log")
+        .arg(&log)
+        .args(&["exit {}", "0", "1", "0", "2"])
+        .assert()
+        .code(2);
+    parallel()
+        .arg("--log")
+        .arg(&log)
+        .args(&["--resume-failed", "-k", "echo {}", "0", "1", "0", "2"])
+        .assert()
+        .success()
+        .stdout("1\n2\n");
+    let mut rerun: Vec<u64> = joblog_records(&log)[4..]
+        .iter()
+        .map(|record| record["seq"].as_u64().unwrap())
+        .collect();
+    rerun.sort();
+    assert_eq!(rerun, [1, 3]);
+}
+
+#[test]
+fn retries_run_the_failed_jobs_again() {
+    let counter = scratch("retries-counter");
+    let log = scratch("retries-log");
+    parallel()
+        .arg("--log")
+        .arg(&log)
+        .args(&["--retries", "2"])
+        .arg(format!(
+            "echo {{}} >> {0}; [ $(wc -l < {0}) -ge 3 ]",
+            counter.display()
+        ))
+        .arg("x")
+        .assert()
+        .success();
+    let records = joblog_records(&log);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["attempts"], 3);
+    assert_eq!(records[0]["exit_code"], 0);
+}
+
+#[test]
+fn timeout_records_the_exit_code_124() {
+    let log = scratch("timeout");
+    let started = std::time::Instant::now();
+    parallel()
+        .arg("--log")
+        .arg(&log)
+        .args(&["--timeout", "1s", "sleep {}", "10"])
+        .assert()
+        .code(1);
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(joblog_records(&log)[0]["exit_code"], 124);
+}
+
+#[test]
+fn delay_waits_between_the_job_starts() {
+    let started = std::time::Instant::now();
+    parallel()
+        .args(&["-j", "4", "--delay", "0.25", "true", "a", "b", "c", "d"])
+        .assert()
+        .success();
+    assert!(started.elapsed() >= std::time::Duration::from_millis(750));
+}
+
+#[test]
+fn interrupt_leaves_no_job_running() {
+    let dir = scratch("sigint");
+    std::fs::create_dir_all(&dir).unwrap();
+    let (a, b) = (dir.join("a"), dir.join("b"));
+    let (output, elapsed) = interrupt(&[
+        "-j",
+        "2",
+        "sleep 1; touch {}",
+        a.to_str().unwrap(),
+        b.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(130));
+    assert!(elapsed < std::time::Duration::from_secs(1));
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    assert!(!a.exists());
+    assert!(!b.exists());
+}
+
+#[test]
+fn round_robin_fails_the_commands_that_stop_reading_their_blocks() {
+    let input: String = (0..50_000).map(|i| format!("{}\n", i)).collect();
+    parallel()
+        .args(&["--pipe", "--round-robin", "-j", "1", "true"])
+        .write_stdin(input)
+        .timeout(std::time::Duration::from_secs(10))
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("could not write block 1"));
+}